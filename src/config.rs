@@ -0,0 +1,288 @@
+use crate::data::CompositeStep;
+use crate::scripts::define_metadata::{get_dataset_metadata, MODIS_SIZES, SAMPLE_TYPES};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, ffi::OsString, fs};
+
+// MODIS ET is an 8 day sum. mm/day divides by the number of days in the composite
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EtUnits {
+    #[default]
+    MmPer8Day,
+    MmPerDay,
+}
+
+// MODIS LE is the mean daily energy over the composite
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeUnits {
+    #[default]
+    JPerM2PerDay,
+    MjPerM2PerDay,
+    WPerM2,
+}
+
+// How to fill a variable that has both terra and aqua products.
+// combined uses the MCD product where one exists (Lai and Fpar) and the mean of terra and aqua otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorCombination {
+    #[default]
+    Terra,
+    Aqua,
+    PreferTerra,
+    PreferAqua,
+    Mean,
+    BestQc,
+    Combined,
+}
+
+// How 16 day composites (MOD13A2, MYD13A2, VNP13A1) fill the 8 day rows.
+// gaps only fills the row the composite starts on, repeat fills both rows the composite covers,
+// composite_doy fills both rows but only with the pixels whose composite day of the year falls in that row.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemporalAlignment {
+    #[default]
+    Gaps,
+    Repeat,
+    CompositeDoy,
+}
+
+// How the composite series are put onto daily rows for {site}_daily.csv.
+// step holds each composite value over its window, linear and spline interpolate between the window centers
+// weighted by the goodpix of the composites either side
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DailyInterpolation {
+    Step,
+    Linear,
+    Spline,
+}
+
+// Filter used for the smoothed and filled Lai, Fpar, EVI and NDVI columns, weighted by goodpix.
+// double_logistic is fitted to each year separately, upper_envelope raises values below a savitzky-golay fit
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMethod {
+    SavitzkyGolay,
+    Whittaker,
+    DoubleLogistic,
+    UpperEnvelope,
+}
+
+// Format of the main per site table. the other outputs (daily, phenology, comparison) are always csv
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Parquet,
+    // one CF netcdf file per site, {site}.nc
+    Netcdf,
+}
+
+// wide has one row per composite and a column per variable (see get_column_descriptors),
+// long has one row per variable: site, date, variable, value, goodpix, source, qc_filtered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLayout {
+    #[default]
+    Wide,
+    Long,
+}
+
+// A dataset added to or changed in the catalog, one [[datasets]] table each.
+// fields left out keep the values of a built in dataset (get_dataset_metadata) of the same name,
+// a new dataset needs at least product, data_layer and qc_name, e.g.
+// [[datasets]]
+// name = "Snow_Albedo_Daily_Tile"
+// product = "MOD10A1"
+// data_layer = "Snow_Albedo_Daily_Tile"
+// qc_name = "NDSI_Snow_Cover_Basic_QA"
+// data_type = "u8"
+// fill_values = [101, 111, 125, 137, 139, 150, 151, 250, 251, 252, 253, 254, 255]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CatalogDataset {
+    pub name: String,
+    // output column, the lowercase name by default. goodpix goes in {column}_goodpix
+    pub column: Option<String>,
+    pub product: Option<String>,
+    pub data_layer: Option<String>,
+    pub qc_name: Option<String>,
+    pub modis_size: Option<String>,
+    pub data_type: Option<String>,
+    pub qc_type: Option<String>,
+    pub scale_factor: Option<f64>,
+    pub add_offset: Option<f64>,
+    pub units: Option<String>,
+    // raw values that are fill
+    pub fill_values: Option<Vec<f64>>,
+    // a pixel is good quality when qc & good_qc_mask is 0, all bits by default
+    pub good_qc_mask: Option<u64>,
+}
+
+// Settings read from the optional config file passed after the flux data file.
+// Anything left out of the file falls back to the defaults below.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub modis_dir: String,
+    pub output_dir: String,
+    pub output_format: OutputFormat,
+    // csv and parquet. write every site to {output_dir}/asia_flux_modis.csv or .parquet instead of a file per site
+    // ({output_dir}/site_code={site}/{site}.parquet for parquet). netcdf is always a file per site
+    pub merge_sites: bool,
+    // csv and parquet, netcdf is always wide
+    pub output_layout: OutputLayout,
+    // rows are written for start_date..=end_date, e.g. start_date = "2000-01-01"
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    // only write rows for the years the tower has data (SYEAR..=EYEAR), widened by tower_period_margin years each side.
+    // still limited to start_date..=end_date
+    pub tower_period_only: bool,
+    pub tower_period_margin: i32,
+    pub et_units: EtUnits,
+    pub le_units: LeUnits,
+    pub sensor_combination: SensorCombination,
+    // also write {site}_modis_viirs.csv with MODIS and VIIRS values side by side
+    pub write_sensor_comparison: bool,
+    // by dataset name, e.g. NDVI = "repeat". datasets left out use gaps
+    pub temporal_alignment: HashMap<String, TemporalAlignment>,
+    // also extract the composite day of the year and LST view time/angle layers
+    pub extract_acquisition_layers: bool,
+    // by dataset name, e.g. Npp = "annual", for built in and [[datasets]] datasets alike.
+    // datasets left out use the step in get_dataset_metadata (8 day for [[datasets]] ones).
+    // the rows stay on the 8 day grid of the input csv whatever the step, see get_row_doys
+    pub composite_step: HashMap<String, CompositeStep>,
+    // also write {site}_daily.csv with the composites interpolated to every day. no daily file when left out
+    pub daily_interpolation: Option<DailyInterpolation>,
+    // composites with a lower goodpix fraction (0 - 1) are left out of the daily interpolation
+    pub daily_min_goodpix: f32,
+    // smoothed and gap filled vegetation columns. left empty when no method is set
+    pub smoothing: Option<SmoothingMethod>,
+    // rows each side of the row being smoothed and the polynomial order, also used by upper_envelope
+    pub savitzky_golay_half_window: usize,
+    pub savitzky_golay_order: usize,
+    // larger is smoother
+    pub whittaker_lambda: f64,
+    pub upper_envelope_iterations: usize,
+    // also write {site}_phenology.csv with NDVI, EVI and tower gpp transition dates per year
+    pub write_phenology: bool,
+    // fractions of the seasonal amplitude for green-up/dormancy and maturity/senescence
+    pub phenology_onset_fraction: f64,
+    pub phenology_maturity_fraction: f64,
+    // NDWI, LSWI, NIRv, kNDVI, EVI2, NBR, SAVI and MSAVI computed per pixel from the MCD43A4 bands
+    pub derived_indices: bool,
+    // extra index written to the band_math column, e.g. "(b4 - b2) / (b4 + b2)". needs derived_indices
+    pub band_math_expression: Option<String>,
+    // highest BRDF_Albedo_Band_Mandatory_Quality value used for derived indices, 0 full inversion, 1 magnitude inversion
+    pub brdf_max_qc: u64,
+    // values in the flux data file that mean no measurement, compared as numbers when they are numbers (-9999 matches -9999.0).
+    // anything else that isn't a number is reported and left empty too
+    pub missing_values: Vec<String>,
+    // datasets added to or changed in the built in catalog, see CatalogDataset
+    pub datasets: Vec<CatalogDataset>,
+    // columns of the wide csv, parquet and netcdf output in the order given, e.g. ["site_code", "year", "doy", "gpp", "lai"].
+    // every column when left out
+    pub output_columns: Option<Vec<String>>,
+    // sha256 of every raster read in the provenance sidecars, slow for the large tiles. size and modification time otherwise
+    pub provenance_checksums: bool,
+    // sqlite database the data and qc values of every window read are kept in, e.g. "/data/asia_flux_modis_cache.sqlite".
+    // can be shared by runs with other flux files and output dirs, a window is only read again once its files change.
+    // no cache when left out
+    pub extraction_cache: Option<String>,
+    // leave the pixels the MOD09A1 state layer flags as cloudy, mixed, cloud shadow or snow out of the sur_refl_b01 - b07 means.
+    // sur_refl_qc_500m only rates the retrieval, the state layer is the only cloud mask the product has
+    pub sur_refl_state_mask: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            modis_dir: String::from("/modis/ORG/binary_data"),
+            output_dir: String::from("./output"),
+            output_format: OutputFormat::default(),
+            merge_sites: false,
+            output_layout: OutputLayout::default(),
+            start_date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            end_date: NaiveDate::from_ymd_opt(2020, 12, 31).unwrap(),
+            tower_period_only: false,
+            tower_period_margin: 0,
+            et_units: EtUnits::default(),
+            le_units: LeUnits::default(),
+            sensor_combination: SensorCombination::default(),
+            write_sensor_comparison: false,
+            temporal_alignment: HashMap::new(),
+            extract_acquisition_layers: false,
+            composite_step: HashMap::new(),
+            daily_interpolation: None,
+            daily_min_goodpix: 0.0,
+            smoothing: None,
+            savitzky_golay_half_window: 3,
+            savitzky_golay_order: 2,
+            whittaker_lambda: 10.0,
+            upper_envelope_iterations: 5,
+            write_phenology: false,
+            phenology_onset_fraction: 0.1,
+            phenology_maturity_fraction: 0.9,
+            derived_indices: false,
+            band_math_expression: None,
+            brdf_max_qc: 1,
+            missing_values: vec![String::from("-9999"), String::from("NA"), String::new()],
+            datasets: vec![],
+            output_columns: None,
+            provenance_checksums: false,
+            extraction_cache: None,
+            sur_refl_state_mask: true,
+        }
+    }
+}
+
+pub fn load_config(config_path: Option<OsString>) -> Result<Config, Box<dyn Error>> {
+    match config_path {
+        None => Ok(Config::default()),
+        Some(config_path) => {
+            let contents = fs::read_to_string(config_path)?;
+            let config: Config = toml::from_str(&contents)?;
+            // a new dataset can't be found on disk without these
+            for dataset in &config.datasets {
+                let is_new = get_dataset_metadata(&dataset.name).dataset.is_empty();
+                if is_new
+                    && (dataset.product.is_none()
+                        || dataset.data_layer.is_none()
+                        || dataset.qc_name.is_none())
+                {
+                    return Err(From::from(format!(
+                        "dataset {}: product, data_layer and qc_name are needed for a dataset that isn't built in",
+                        dataset.name
+                    )));
+                }
+                // anything else would be read with the wrong sample size or grid part way through a site
+                for (key, value, supported) in [
+                    ("data_type", &dataset.data_type, &SAMPLE_TYPES[..]),
+                    ("qc_type", &dataset.qc_type, &SAMPLE_TYPES[..]),
+                    ("modis_size", &dataset.modis_size, &MODIS_SIZES[..]),
+                ] {
+                    if let Some(value) = value {
+                        if !supported.contains(&value.as_str()) {
+                            return Err(From::from(format!(
+                                "dataset {}: {key} \"{value}\" isn't one of {}",
+                                dataset.name,
+                                supported.join(", ")
+                            )));
+                        }
+                    }
+                }
+            }
+            if config.merge_sites && config.output_format == OutputFormat::Netcdf {
+                return Err(From::from(
+                    "merge_sites is only for csv and parquet, netcdf is written per site",
+                ));
+            }
+            Ok(config)
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
 
 #[derive(Debug)]
 pub struct TowerEntryData {
@@ -8,6 +10,42 @@ pub struct TowerEntryData {
     pub lon: f64,
 }
 
+// the satellite a product comes from. the combined products merge both terra and aqua
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sensor {
+    Terra,
+    Aqua,
+    Combined,
+}
+
+impl Sensor {
+    // prefix of the product name, also written to the *_source columns
+    pub fn code(&self) -> &'static str {
+        match self {
+            Sensor::Terra => "MOD",
+            Sensor::Aqua => "MYD",
+            Sensor::Combined => "MCD",
+        }
+    }
+}
+
+// how often a product has a new file. the output rows are 8 days apart regardless,
+// each row reads the file whose period covers it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeStep {
+    Daily,
+    #[default]
+    EightDay,
+    SixteenDay,
+    Monthly,
+    Annual,
+}
+
+// first and last day a composite value covers
+pub type CompositeWindow = (NaiveDate, NaiveDate);
+
+#[derive(Serialize)]
 pub struct DatasetMetadata {
     pub dataset: String,
     pub product: String,
@@ -15,9 +53,20 @@ pub struct DatasetMetadata {
     pub data_bytes: u64,
     pub qc_bytes: u64,
     pub modis_size: String,
+    // sample types of the binary files: u8, i8, u16, i16, u32, i32, f32 or f64
     pub data_type: String,
     pub qc_type: String,
     pub scale_factor: f64,
+    pub units: String,
+    // added after the scale factor is applied
+    pub add_offset: f64,
+    pub composite_step: CompositeStep,
+    // layer part of the file name for datasets from the config catalog. the built in ones work it out from the dataset name
+    pub data_layer: String,
+    // fill values and qc rule of datasets from the config catalog, a pixel is good quality when qc & good_qc_mask is 0.
+    // None uses the rules of the built in products in read_modis_values
+    pub fill_values: Vec<f64>,
+    pub good_qc_mask: Option<u64>,
 }
 
 impl Default for DatasetMetadata {
@@ -32,161 +81,212 @@ impl Default for DatasetMetadata {
             data_type: String::new(),
             qc_type: String::new(),
             scale_factor: 0.0,
+            units: String::new(),
+            add_offset: 0.0,
+            composite_step: CompositeStep::default(),
+            data_layer: String::new(),
+            fill_values: vec![],
+            good_qc_mask: None,
         }
     }
 }
 
+// type of an output column. csv writes the values as text, parquet and netcdf keep the type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    Text,
+    Int32,
+    Int64,
+    UInt8,
+    Float32,
+    Float64,
+    Boolean,
+}
+
+// a value of an output column, the variant matches the ColumnType of the column
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ColumnValue {
+    Text(String),
+    Int32(i32),
+    Int64(i64),
+    UInt8(u8),
+    Float32(f32),
+    Float64(f64),
+    Boolean(bool),
+}
+
+impl ColumnValue {
+    // the value as a number for the long layout, true is 1 and false 0. None for text
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ColumnValue::Text(_) => None,
+            ColumnValue::Int32(x) => Some(f64::from(*x)),
+            ColumnValue::Int64(x) => Some(*x as f64),
+            ColumnValue::UInt8(x) => Some(f64::from(*x)),
+            ColumnValue::Float32(x) => Some(f64::from(*x)),
+            ColumnValue::Float64(x) => Some(*x),
+            ColumnValue::Boolean(x) => Some(if *x { 1.0 } else { 0.0 }),
+        }
+    }
+}
+
+// floats are written the way the csv serializer writes them, 1.0 rather than 1
+impl fmt::Display for ColumnValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnValue::Text(x) => write!(f, "{x}"),
+            ColumnValue::Int32(x) => write!(f, "{x}"),
+            ColumnValue::Int64(x) => write!(f, "{x}"),
+            ColumnValue::UInt8(x) => write!(f, "{x}"),
+            ColumnValue::Float32(x) => write!(f, "{x:?}"),
+            ColumnValue::Float64(x) => write!(f, "{x:?}"),
+            ColumnValue::Boolean(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+// what an output column holds, decides where its values come from and how the long layout and netcdf treat it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnKind {
+    // site_code, lat, lon, syear, eyear, year and doy
+    Site,
+    // a column of the flux data file
+    Flux,
+    // window mean of a dataset, and its good pixel fraction and sensor
+    Value,
+    Goodpix,
+    Source,
+    // smoothed and gap filled series of a dataset
+    Smoothed,
+    Filled,
+    // anything else worked out from a window, e.g. land cover composition or the snow fraction
+    Statistic,
+}
+
+// One column of the wide output. get_column_descriptors builds them from the dataset catalog and config,
+// csv, parquet, netcdf and the long layout are all written from these.
+#[derive(Clone, Debug)]
+pub struct ColumnDescriptor {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub kind: ColumnKind,
+    // dataset and product the column is read from, None for the site and flux columns
+    pub dataset: Option<String>,
+    pub product: Option<String>,
+    // units of the written values and the product's own scale factor, the values are already scaled
+    pub units: Option<String>,
+    pub scale_factor: Option<f64>,
+    // low quality or cloudy pixels are left out of the value rather than only counted in goodpix
+    pub qc_filtered: bool,
+}
+
+#[allow(dead_code)]
 #[derive(Default, Debug, Deserialize, Serialize)]
 pub struct Test {
     pub id: u16,
 }
 
-// ideally I think these should all be some sort of option, I just got lazy.
-#[derive(Default, Deserialize, Serialize)]
+// the flux columns are None outside of the tower years and where the input has a missing value sentinel,
+// so every missing value is written the same way (empty in csv, null in parquet, _FillValue in netcdf)
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct NewRecord<'a> {
+    // not read back from checkpoints, so rows can outlive the text they were read from
+    #[serde(skip_deserializing)]
+    pub site_code: &'a str,
+    pub lat: f64,
+    pub lon: f64,
+    pub syear: i32,
+    pub eyear: i32,
+    pub year: i32,
+    pub doy: u32,
+    pub solar_radiation: Option<f64>,
+    pub air_temperature: Option<f64>,
+    pub vpd: Option<f64>,
+    pub sensible_heat: Option<f64>,
+    pub evapotranspiration: Option<f64>,
+    pub respiration: Option<f64>,
+    pub nee: Option<f64>,
+    pub gpp: Option<f64>,
+    // everything read from the rasters by output column name, see get_column_descriptors. missing values aren't in it.
+    // left empty when a row is read from a csv, see read_output_rows
+    #[serde(default)]
+    pub values: BTreeMap<String, ColumnValue>,
+    // days the composite behind each dataset value covers, by dataset name, from find_aligned_composite.
+    // for the daily output, not written to the outputs so rows read from a csv don't have it
+    #[serde(default)]
+    pub composite_windows: BTreeMap<String, CompositeWindow>,
+}
+
+impl NewRecord<'_> {
+    // sets a column, None removes the value a reused record had from its last row
+    pub fn set_value(&mut self, column: &str, value: Option<ColumnValue>) {
+        match value {
+            Some(value) => self.values.insert(column.to_string(), value),
+            None => self.values.remove(column),
+        };
+    }
+
+    pub fn get_f64(&self, column: &str) -> Option<f64> {
+        self.values.get(column).and_then(ColumnValue::as_f64)
+    }
+
+    pub fn get_f32(&self, column: &str) -> Option<f32> {
+        self.get_f64(column).map(|x| x as f32)
+    }
+
+    pub fn get_text(&self, column: &str) -> Option<String> {
+        match self.values.get(column) {
+            Some(ColumnValue::Text(text)) => Some(text.clone()),
+            _ => None,
+        }
+    }
+}
+
+// one row of the MODIS vs VIIRS comparison csv
+#[derive(Serialize)]
+pub struct SensorComparisonRecord<'a> {
     pub site_code: &'a str,
-    pub lat: String,
-    pub lon: String,
-    pub syear: String,
-    pub eyear: String,
     pub year: i32,
     pub doy: u32,
-    pub solar_radiation: String,
-    pub air_temperature: String,
-    pub vpd: String,
-    pub sensible_heat: String,
-    pub evapotranspiration: String,
-    pub respiration: String,
-    pub nee: String,
-    pub gpp: String,
-    pub lai: Option<f64>,
-    pub lai_goodpix: Option<f32>,
-    pub fpar: Option<f64>,
-    pub fpar_goodpix: Option<f32>,
-    pub evi: Option<f64>,
-    pub evi_goodpix: Option<f32>,
-    pub ndvi: Option<f64>,
-    pub ndvi_goodpix: Option<f32>,
-    pub lst_day: Option<f64>,
-    pub lst_day_goodpix: Option<f32>,
-    pub lst_night: Option<f64>,
-    pub lst_night_goodpix: Option<f32>,
-    pub nadir_ref_band1: Option<f64>,
-    pub nadir_ref_band1_goodpix: Option<f32>,
-    pub nadir_ref_band2: Option<f64>,
-    pub nadir_ref_band2_goodpix: Option<f32>,
-    pub nadir_ref_band3: Option<f64>,
-    pub nadir_ref_band3_goodpix: Option<f32>,
-    pub nadir_ref_band4: Option<f64>,
-    pub nadir_ref_band4_goodpix: Option<f32>,
-    pub nadir_ref_band5: Option<f64>,
-    pub nadir_ref_band5_goodpix: Option<f32>,
-    pub nadir_ref_band6: Option<f64>,
-    pub nadir_ref_band6_goodpix: Option<f32>,
-    pub nadir_ref_band7: Option<f64>,
-    pub nadir_ref_band7_goodpix: Option<f32>,
-}
-
-// pub trait CreateHeader {
-//     fn create_header() -> NewRecord;
-// }
-
-// impl CreateHeader for NewRecord {
-//     fn create_header() -> NewRecord {
-//         NewRecord {
-//             site_code: "SiteCode".to_string(),
-//             lat: "LAT".to_string(),
-//             lon: "LON".to_string(),
-//             syear: "SYEAR".to_string(),
-//             eyear: "EYEAR".to_string(),
-//             year: "Year".to_string(),
-//             doy: "DOY".to_string(),
-//             solar_radiation: "SolarRadiation".to_string(),
-//             air_temperature: "AirTemperature".to_string(),
-//             vpd: "VPD".to_string(),
-//             sensible_heat: "SensibleHeat".to_string(),
-//             evapotranspiration: "Evapotranspiration".to_string(),
-//             respiration: "Respiration".to_string(),
-//             nee: "NEE".to_string(),
-//             gpp: "GPP".to_string(),
-//             lai: "Lai".to_string(),
-//             lai_goodpix: "per_goodpix".to_string(),
-//             fpar: "Fpar".to_string(),
-//             fpar_goodpix: "per_goodpix".to_string(),
-//             evi: "EVI".to_string(),
-//             evi_goodpix: "per_goodpix".to_string(),
-//             ndvi: "NDVI".to_string(),
-//             ndvi_goodpix: "per_goodpix".to_string(),
-//             lst_day: "LST_Day".to_string(),
-//             lst_day_goodpix: "per_goodpix".to_string(),
-//             lst_night: "LST_Night".to_string(),
-//             lst_night_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band1: "Nadir_Reflectance_Band1".to_string(),
-//             nadir_ref_band1_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band2: "Nadir_Reflectance_Band2".to_string(),
-//             nadir_ref_band2_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band3: "Nadir_Reflectance_Band3".to_string(),
-//             nadir_ref_band3_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band4: "Nadir_Reflectance_Band4".to_string(),
-//             nadir_ref_band4_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band5: "Nadir_Reflectance_Band5".to_string(),
-//             nadir_ref_band5_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band6: "Nadir_Reflectance_Band6".to_string(),
-//             nadir_ref_band6_goodpix: "per_goodpix".to_string(),
-//             nadir_ref_band7: "Nadir_Reflectance_Band7".to_string(),
-//             nadir_ref_band7_goodpix: "per_goodpix".to_string(),
-//         }
-//     }
-// }
-
-// pub trait Vectorize {
-//     fn vectorize(&self) -> Vec<&str>;
-// }
-
-// impl Vectorize for NewRecord {
-//     fn vectorize(&self) -> Vec<&str> {
-//         vec![
-//             self.site_code.as_str(),
-//             self.lat.as_str(),
-//             self.lon.as_str(),
-//             self.syear.as_str(),
-//             self.eyear.as_str(),
-//             self.year.as_str(),
-//             self.doy.as_str(),
-//             self.solar_radiation.as_str(),
-//             self.air_temperature.as_str(),
-//             self.vpd.as_str(),
-//             self.sensible_heat.as_str(),
-//             self.evapotranspiration.as_str(),
-//             self.respiration.as_str(),
-//             self.nee.as_str(),
-//             self.gpp.as_str(),
-//             self.lai.as_str(),
-//             self.lai_goodpix.as_str(),
-//             self.fpar.as_str(),
-//             self.fpar_goodpix.as_str(),
-//             self.evi.as_str(),
-//             self.evi_goodpix.as_str(),
-//             self.ndvi.as_str(),
-//             self.ndvi_goodpix.as_str(),
-//             self.lst_day.as_str(),
-//             self.lst_day_goodpix.as_str(),
-//             self.lst_night.as_str(),
-//             self.lst_night_goodpix.as_str(),
-//             self.nadir_ref_band1.as_str(),
-//             self.nadir_ref_band1_goodpix.as_str(),
-//             self.nadir_ref_band2.as_str(),
-//             self.nadir_ref_band2_goodpix.as_str(),
-//             self.nadir_ref_band3.as_str(),
-//             self.nadir_ref_band3_goodpix.as_str(),
-//             self.nadir_ref_band4.as_str(),
-//             self.nadir_ref_band4_goodpix.as_str(),
-//             self.nadir_ref_band5.as_str(),
-//             self.nadir_ref_band5_goodpix.as_str(),
-//             self.nadir_ref_band6.as_str(),
-//             self.nadir_ref_band6_goodpix.as_str(),
-//             self.nadir_ref_band7.as_str(),
-//             self.nadir_ref_band7_goodpix.as_str(),
-//         ]
-//     }
-// }
+    pub modis_dataset: &'a str,
+    pub viirs_dataset: &'a str,
+    pub modis: Option<f64>,
+    pub modis_goodpix: Option<f32>,
+    pub viirs: Option<f64>,
+    pub viirs_goodpix: Option<f32>,
+    pub difference: Option<f64>,
+}
+
+// One row of the long layout: a single variable of a composite row.
+// qc_filtered is true when low quality pixels were left out of the value rather than only counted in goodpix
+#[derive(Serialize)]
+pub struct LongRecord<'a> {
+    pub site: &'a str,
+    pub date: NaiveDate,
+    pub variable: String,
+    pub value: f64,
+    pub goodpix: Option<f32>,
+    pub source: Option<String>,
+    pub qc_filtered: bool,
+}
+
+// One row of {site}_phenology.csv: transition dates of a fitted series for one year.
+// series is NDVI, EVI or tower_gpp, only the tower_gpp rows have the carbon uptake period from nee
+#[derive(Serialize)]
+pub struct PhenologyRecord<'a> {
+    pub site_code: &'a str,
+    pub year: i32,
+    pub series: &'a str,
+    pub green_up: Option<u32>,
+    pub maturity: Option<u32>,
+    pub senescence: Option<u32>,
+    pub dormancy: Option<u32>,
+    pub season_length: Option<u32>,
+    pub peak_doy: Option<u32>,
+    pub peak_value: Option<f64>,
+    pub amplitude: Option<f64>,
+    pub integrated: Option<f64>,
+    pub cup_start: Option<u32>,
+    pub cup_end: Option<u32>,
+    pub cup_length: Option<u32>,
+}
@@ -0,0 +1,318 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::provenance::{FileInfo, RasterLookup};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// How far each site got, in {output_dir}/checkpoint/manifest.json.
+// the rows extracted so far for a site are in {output_dir}/checkpoint/{site}.jsonl, so a site that was stopped part way
+// carries on from the row after last_date instead of reading every raster again.
+// the manifest is saved every CHECKPOINT_ROWS rows and when a site is done, a run that stops reads the rows since again
+#[derive(Default, Deserialize, Serialize)]
+pub struct Checkpoint {
+    // the rows are only reused with the same config and flux file
+    pub config: serde_json::Value,
+    pub flux_sha256: Option<String>,
+    // the checkpoint was written by update rather than a full run. an update only carries on from an update,
+    // the sites a full run finished are complete without the composites that arrived since
+    #[serde(default)]
+    pub update: bool,
+    pub sites: BTreeMap<String, SiteCheckpoint>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct SiteCheckpoint {
+    // date of the last row saved to {site}.jsonl, and how many rows that is
+    pub last_date: Option<NaiveDate>,
+    pub rows: usize,
+    // every output of the site has been written
+    pub complete: bool,
+}
+
+// one line of {site}.jsonl, a row as it was before smoothing
+#[derive(Deserialize, Serialize)]
+pub struct CheckpointRow<'a> {
+    // site_code isn't read back, it's set from the site being processed
+    pub record: NewRecord<'a>,
+    // rasters read for the row, for the provenance sidecar
+    pub raster_lookups: Vec<RasterLookup>,
+}
+
+// outputs are written to {path}.tmp and renamed once they are complete, so a file that exists is never half written
+pub fn get_temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+// the temp file is on disk before it's renamed, so a crash can't leave an empty or half written file under the name
+pub fn finish_temp_file(path: &Path) -> Result<(), Box<dyn Error>> {
+    let temp_path = get_temp_path(path);
+    File::open(&temp_path)?.sync_all()?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    fs::write(get_temp_path(path), contents)?;
+    finish_temp_file(path)
+}
+
+// rows added to {site}.jsonl between saves of the manifest, about a year of a site
+const CHECKPOINT_ROWS: usize = 46;
+
+fn get_checkpoint_dir(config: &Config) -> PathBuf {
+    Path::new(&config.output_dir).join("checkpoint")
+}
+
+fn get_checkpoint_rows_path(site_code: &str, config: &Config) -> PathBuf {
+    get_checkpoint_dir(config).join(format!("{site_code}.jsonl"))
+}
+
+// the checkpoint of the last run, or an empty one when there isn't one, it can't be read or the config or flux file changed since.
+// an update starts from an empty one too unless the last run was an update that stopped part way
+pub fn load_checkpoint(
+    flux_file: &FileInfo,
+    update: bool,
+    config: &Config,
+) -> Result<Checkpoint, Box<dyn Error>> {
+    fs::create_dir_all(get_checkpoint_dir(config))?;
+    let empty = Checkpoint {
+        config: serde_json::to_value(config)?,
+        flux_sha256: flux_file.sha256.clone(),
+        update,
+        sites: BTreeMap::new(),
+    };
+    let manifest_path = get_checkpoint_dir(config).join("manifest.json");
+    let Ok(contents) = fs::read_to_string(&manifest_path) else {
+        return Ok(empty);
+    };
+    let mut checkpoint: Checkpoint = match serde_json::from_str(&contents) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            println!(
+                "{} can't be read ({e}), every site is processed again",
+                manifest_path.display()
+            );
+            return Ok(empty);
+        }
+    };
+    if checkpoint.config != empty.config || checkpoint.flux_sha256 != empty.flux_sha256 {
+        println!("config or flux file changed since the last run, every site is processed again");
+        return Ok(empty);
+    }
+    if update && (!checkpoint.update || checkpoint.sites.values().all(|site| site.complete)) {
+        return Ok(empty);
+    }
+    // a full run can carry on from an update, its rows are whole rows too
+    checkpoint.update = update;
+    Ok(checkpoint)
+}
+
+pub fn save_checkpoint(checkpoint: &Checkpoint, config: &Config) -> Result<(), Box<dyn Error>> {
+    write_atomic(
+        &get_checkpoint_dir(config).join("manifest.json"),
+        serde_json::to_string_pretty(checkpoint)?.as_bytes(),
+    )
+}
+
+// The rows of a site saved by earlier runs, as many as the manifest says were saved.
+// a line written when the run stopped isn't counted, the file is cut back to the counted rows before more are added
+pub fn read_checkpoint_rows<'a>(
+    site_code: &'a str,
+    rows: usize,
+    config: &Config,
+) -> Result<Vec<CheckpointRow<'a>>, Box<dyn Error>> {
+    let path = get_checkpoint_rows_path(site_code, config);
+    let contents = fs::read_to_string(&path).unwrap_or_default();
+    let lines: Vec<&str> = contents.lines().take(rows).collect();
+    if lines.len() < rows {
+        return Err(From::from(format!(
+            "{site_code}: checkpoint has {} of {rows} rows, remove {} to start the site again",
+            lines.len(),
+            path.display()
+        )));
+    }
+    let mut checkpoint_rows = vec![];
+    for line in &lines {
+        let mut checkpoint_row: CheckpointRow = serde_json::from_str(line)?;
+        checkpoint_row.record.site_code = site_code;
+        checkpoint_rows.push(checkpoint_row);
+    }
+    let mut kept = lines.join("\n");
+    if !kept.is_empty() {
+        kept.push('\n');
+    }
+    write_atomic(&path, kept.as_bytes())?;
+    Ok(checkpoint_rows)
+}
+
+// Saves the manifest with the site as far as site_checkpoint, once its rows are on disk
+pub fn save_site_checkpoint(
+    checkpoint: &mut Checkpoint,
+    site_code: &str,
+    site_checkpoint: &SiteCheckpoint,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let path = get_checkpoint_rows_path(site_code, config);
+    if path.exists() {
+        File::open(path)?.sync_data()?;
+    }
+    checkpoint
+        .sites
+        .insert(site_code.to_string(), site_checkpoint.clone());
+    save_checkpoint(checkpoint, config)
+}
+
+// Adds a row to {site}.jsonl, the manifest is saved with every CHECKPOINT_ROWS rows.
+// a row in the file only counts once the manifest has been saved
+pub fn add_checkpoint_row(
+    checkpoint: &mut Checkpoint,
+    site_checkpoint: &mut SiteCheckpoint,
+    rcrd: &NewRecord,
+    raster_lookups: &[RasterLookup],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let checkpoint_row = CheckpointRow {
+        record: rcrd.clone(),
+        raster_lookups: raster_lookups.to_vec(),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_checkpoint_rows_path(rcrd.site_code, config))?;
+    writeln!(file, "{}", serde_json::to_string(&checkpoint_row)?)?;
+
+    site_checkpoint.last_date = NaiveDate::from_yo_opt(rcrd.year, rcrd.doy);
+    site_checkpoint.rows += 1;
+    if site_checkpoint.rows.is_multiple_of(CHECKPOINT_ROWS) {
+        save_site_checkpoint(checkpoint, rcrd.site_code, site_checkpoint, config)?;
+    }
+    Ok(())
+}
+
+pub fn remove_checkpoint_rows(site_code: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let path = get_checkpoint_rows_path(site_code, config);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(name: &str) -> Config {
+        let output_dir =
+            std::env::temp_dir().join(format!("checkpoint_{name}_{}", std::process::id()));
+        toml::from_str(&format!("output_dir = {:?}", output_dir.to_str().unwrap())).unwrap()
+    }
+
+    fn flux_file(sha256: &str) -> FileInfo {
+        FileInfo {
+            path: "flux.csv".to_string(),
+            size: None,
+            modified: None,
+            sha256: Some(sha256.to_string()),
+        }
+    }
+
+    #[test]
+    fn resumes_from_the_counted_rows() {
+        let config = test_config("resume");
+        let mut checkpoint = load_checkpoint(&flux_file("a"), false, &config).unwrap();
+        assert!(checkpoint.sites.is_empty());
+        let mut site_checkpoint = SiteCheckpoint::default();
+        for doy in [1, 9, 17] {
+            let rcrd = NewRecord {
+                site_code: "JP-Tkc",
+                year: 2010,
+                doy,
+                gpp: Some(doy as f64),
+                ..Default::default()
+            };
+            add_checkpoint_row(&mut checkpoint, &mut site_checkpoint, &rcrd, &[], &config).unwrap();
+        }
+        save_site_checkpoint(&mut checkpoint, "JP-Tkc", &site_checkpoint, &config).unwrap();
+        // a line half written when the run stopped
+        let rows_path = get_checkpoint_rows_path("JP-Tkc", &config);
+        let mut file = OpenOptions::new().append(true).open(&rows_path).unwrap();
+        write!(file, "{{\"record\":").unwrap();
+
+        let checkpoint = load_checkpoint(&flux_file("a"), false, &config).unwrap();
+        let site_checkpoint = &checkpoint.sites["JP-Tkc"];
+        assert_eq!(site_checkpoint.rows, 3);
+        assert_eq!(site_checkpoint.last_date, NaiveDate::from_yo_opt(2010, 17));
+        let rows = read_checkpoint_rows("JP-Tkc", site_checkpoint.rows, &config).unwrap();
+        let gpp: Vec<Option<f64>> = rows.iter().map(|row| row.record.gpp).collect();
+        assert_eq!(gpp, vec![Some(1.0), Some(9.0), Some(17.0)]);
+        assert_eq!(rows[0].record.site_code, "JP-Tkc");
+        // the half written line is cut off, so new rows go after the counted ones
+        assert_eq!(fs::read_to_string(&rows_path).unwrap().lines().count(), 3);
+        assert!(read_checkpoint_rows("JP-Tkc", 4, &config).is_err());
+
+        // another flux file starts over
+        assert!(load_checkpoint(&flux_file("b"), false, &config)
+            .unwrap()
+            .sites
+            .is_empty());
+        // so does a manifest that can't be read
+        fs::write(
+            get_checkpoint_dir(&config).join("manifest.json"),
+            "{\"sites\":",
+        )
+        .unwrap();
+        assert!(load_checkpoint(&flux_file("a"), false, &config)
+            .unwrap()
+            .sites
+            .is_empty());
+        fs::remove_dir_all(&config.output_dir).unwrap();
+    }
+
+    #[test]
+    fn update_only_carries_on_from_an_update() {
+        let config = test_config("update");
+        let site_checkpoint = |complete: bool| SiteCheckpoint {
+            last_date: NaiveDate::from_yo_opt(2010, 1),
+            rows: 1,
+            complete,
+        };
+        let save = |update: bool, complete: bool| {
+            let mut checkpoint = load_checkpoint(&flux_file("a"), update, &config).unwrap();
+            checkpoint.update = update;
+            checkpoint
+                .sites
+                .insert("JP-Tkc".to_string(), site_checkpoint(complete));
+            save_checkpoint(&checkpoint, &config).unwrap();
+        };
+        let resumed_sites = |update: bool| {
+            load_checkpoint(&flux_file("a"), update, &config)
+                .unwrap()
+                .sites
+                .len()
+        };
+
+        // a full run that stopped part way
+        save(false, false);
+        assert_eq!(resumed_sites(false), 1);
+        assert_eq!(resumed_sites(true), 0);
+        // an update that stopped part way, a full run can carry on from it too
+        save(true, false);
+        assert_eq!(resumed_sites(true), 1);
+        assert_eq!(resumed_sites(false), 1);
+        // an update that finished
+        save(true, true);
+        assert_eq!(resumed_sites(true), 0);
+
+        fs::remove_dir_all(&config.output_dir).unwrap();
+    }
+}
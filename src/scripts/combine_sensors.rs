@@ -0,0 +1,183 @@
+use crate::config::{Config, SensorCombination};
+use crate::data::*;
+use crate::scripts::checkpoint::{finish_temp_file, get_temp_path};
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::*;
+use crate::scripts::state_flags::get_clear_pixel_filter;
+use crate::scripts::temporal_alignment::find_aligned_composite;
+use csv::WriterBuilder;
+use std::{error::Error, path::PathBuf};
+
+// sensors to read for a dataset, in order of preference
+pub fn get_sensors(dataset_name: &str, config: &Config) -> Vec<Sensor> {
+    let sensors = match config.sensor_combination {
+        SensorCombination::Terra => vec![Sensor::Terra],
+        SensorCombination::Aqua => vec![Sensor::Aqua],
+        SensorCombination::PreferAqua => vec![Sensor::Aqua, Sensor::Terra],
+        SensorCombination::PreferTerra | SensorCombination::Mean | SensorCombination::BestQc => {
+            vec![Sensor::Terra, Sensor::Aqua]
+        }
+        SensorCombination::Combined => {
+            if get_sensor_metadata(dataset_name, Sensor::Combined, config).is_some() {
+                vec![Sensor::Combined]
+            } else {
+                vec![Sensor::Terra, Sensor::Aqua]
+            }
+        }
+    };
+    let available: Vec<Sensor> = sensors
+        .into_iter()
+        .filter(|sensor| get_sensor_metadata(dataset_name, *sensor, config).is_some())
+        .collect();
+    // datasets that only exist for terra are always read from terra
+    if available.is_empty() {
+        vec![Sensor::Terra]
+    } else {
+        available
+    }
+}
+
+// Reads a dataset from every sensor picked by the config and writes one value to the record, along with which sensor it came from.
+// each sensor's composite is looked up on its own dates, so 16 day products like MYD13A2 (which is offset 8 days from MOD13A2)
+// are only found on their own composite start days, unless the dataset has a temporal alignment policy.
+pub fn get_sensor_data<'a>(
+    mut rcrd: NewRecord<'a>,
+    dataset_name: &str,
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> NewRecord<'a> {
+    let tower_entry_data = TowerEntryData {
+        year: rcrd.year,
+        doy: rcrd.doy,
+        lat: rcrd.lat,
+        lon: rcrd.lon,
+    };
+
+    // (sensor, (data average, goodpix percent), days its composite covers) for every sensor with a usable value
+    let mut sensor_values: Vec<(Sensor, (f64, f32), CompositeWindow)> = vec![];
+    for sensor in get_sensors(dataset_name, config) {
+        let mut dm = get_sensor_metadata(dataset_name, sensor, config).unwrap();
+        apply_unit_conversion(&mut dm, year, doy, config);
+        if let Some((data_qc_paths, mut pixel_filter, window)) =
+            find_aligned_composite(&dm, &tower_entry_data, year, doy, config, rasters)
+        {
+            // MOD09A1 bands only average the pixels its state layer has as clear
+            if config.sur_refl_state_mask && dm.dataset.starts_with("sur_refl_b") {
+                if let Some(clear_pixels) =
+                    get_clear_pixel_filter(&dm, &tower_entry_data, year, doy, config, rasters)
+                {
+                    pixel_filter = Some(match pixel_filter {
+                        Some(keep) => keep
+                            .iter()
+                            .zip(&clear_pixels)
+                            .map(|(a, b)| *a && *b)
+                            .collect(),
+                        None => clear_pixels,
+                    });
+                }
+            }
+            if let (Some(data_ave), Some(goodpix_per)) = read_modis_values(
+                &dm,
+                &tower_entry_data,
+                data_qc_paths,
+                pixel_filter.as_deref(),
+                rasters,
+            ) {
+                sensor_values.push((sensor, (data_ave, goodpix_per), window));
+            }
+        }
+    }
+
+    let (values, source, window) = match (config.sensor_combination, sensor_values.as_slice()) {
+        (_, []) => ((None, None), None, None),
+        (SensorCombination::Mean, [_, _]) | (SensorCombination::Combined, [_, _]) => {
+            let data_ave = (sensor_values[0].1 .0 + sensor_values[1].1 .0) / 2.0;
+            let goodpix_per = (sensor_values[0].1 .1 + sensor_values[1].1 .1) / 2.0;
+            let data_ave_round = (data_ave * 10000.0).round() / 10000.0;
+            (
+                (Some(data_ave_round), Some(goodpix_per)),
+                Some(format!(
+                    "{}+{}",
+                    sensor_values[0].0.code(),
+                    sensor_values[1].0.code()
+                )),
+                // the mean covers the days of both composites
+                Some((
+                    sensor_values[0].2 .0.min(sensor_values[1].2 .0),
+                    sensor_values[0].2 .1.max(sensor_values[1].2 .1),
+                )),
+            )
+        }
+        (SensorCombination::BestQc, _) => {
+            // ties go to the first sensor (terra)
+            let (sensor, (data_ave, goodpix_per), window) = sensor_values
+                .iter()
+                .rev()
+                .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+                .unwrap();
+            (
+                (Some(*data_ave), Some(*goodpix_per)),
+                Some(sensor.code().to_string()),
+                Some(*window),
+            )
+        }
+        // terra, aqua, prefer terra/aqua: the first sensor with a value wins
+        (_, [(sensor, (data_ave, goodpix_per), window), ..]) => (
+            (Some(*data_ave), Some(*goodpix_per)),
+            Some(sensor.code().to_string()),
+            Some(*window),
+        ),
+    };
+
+    match window {
+        Some(window) => rcrd
+            .composite_windows
+            .insert(dataset_name.to_string(), window),
+        None => rcrd.composite_windows.remove(dataset_name),
+    };
+
+    rcrd = set_modis_values(rcrd, dataset_name, values, config);
+    set_modis_source(rcrd, dataset_name, source, config)
+}
+
+// Write {site}_modis_viirs.csv with the MODIS and VIIRS values of each row side by side, see get_viirs_comparison_pairs
+pub fn write_sensor_comparison_output(
+    site_code: &str,
+    site_rows: &[NewRecord],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if !config.write_sensor_comparison {
+        return Ok(());
+    }
+    let path = PathBuf::from(format!(
+        "{}/{}_modis_viirs.csv",
+        config.output_dir, site_code
+    ));
+    let mut wtr = WriterBuilder::new().from_path(get_temp_path(&path))?;
+    for rcrd in site_rows {
+        for (modis_dataset, viirs_dataset) in get_viirs_comparison_pairs() {
+            let (modis, modis_goodpix) = get_modis_values(rcrd, modis_dataset, config);
+            let (viirs, viirs_goodpix) = get_modis_values(rcrd, viirs_dataset, config);
+            let difference = match (modis, viirs) {
+                (Some(modis), Some(viirs)) => Some(((viirs - modis) * 10000.0).round() / 10000.0),
+                _ => None,
+            };
+            wtr.serialize(SensorComparisonRecord {
+                site_code,
+                year: rcrd.year,
+                doy: rcrd.doy,
+                modis_dataset,
+                viirs_dataset,
+                modis,
+                modis_goodpix,
+                viirs,
+                viirs_goodpix,
+                difference,
+            })?;
+        }
+    }
+    wtr.flush()?;
+    finish_temp_file(&path)
+}
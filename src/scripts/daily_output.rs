@@ -0,0 +1,371 @@
+use crate::config::{Config, DailyInterpolation};
+use crate::data::*;
+use crate::scripts::checkpoint::{finish_temp_file, get_temp_path};
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::get_modis_values;
+use chrono::prelude::*;
+use csv::WriterBuilder;
+use std::{error::Error, path::PathBuf};
+
+// one written 8 day row of a site: the row date and (value, goodpix, days the composite covers)
+// for every dataset in get_daily_dataset_names()
+pub type CompositeRow = (NaiveDate, Vec<(Option<f64>, Option<f32>, CompositeWindow)>);
+
+// a composite value usable for interpolation. x values are days since the first daily row
+struct Anchor {
+    start: f64,
+    end: f64,
+    center: f64,
+    value: f64,
+    goodpix: f32,
+}
+
+// every dataset with a value column: the built in ones, the derived indices when they are computed
+// and the ones only in the config catalog
+fn get_daily_dataset_names(config: &Config) -> Vec<String> {
+    let mut dataset_names = get_dataset_names();
+    if config.derived_indices {
+        dataset_names.extend(
+            get_derived_index_expressions()
+                .into_iter()
+                .map(|(name, _)| name),
+        );
+        if config.band_math_expression.is_some() {
+            dataset_names.push("band_math".to_string());
+        }
+    }
+    dataset_names.extend(get_catalog_dataset_names(config));
+    dataset_names
+}
+
+pub fn collect_composite_row(
+    rcrd: &NewRecord,
+    row_date: NaiveDate,
+    config: &Config,
+) -> CompositeRow {
+    let values = get_daily_dataset_names(config)
+        .iter()
+        .map(|dataset_name| {
+            let (value, goodpix) = get_modis_values(rcrd, dataset_name, config);
+            let window = rcrd
+                .composite_windows
+                .get(dataset_name)
+                .copied()
+                .unwrap_or_else(|| fallback_composite_window(dataset_name, row_date, config));
+            (value, goodpix, window)
+        })
+        .collect();
+    (row_date, values)
+}
+
+// days covered by the composite of a row read back from an earlier output, which doesn't say which composite it was.
+// monthly and annual values are the composite of the month or year the row is in. a 16 day value could have
+// started on the row or the row before, so it is only taken to cover its own row.
+// the derived indices are read from the nadir bands of the row date, so they cover their own row too
+fn fallback_composite_window(
+    dataset_name: &str,
+    row_date: NaiveDate,
+    config: &Config,
+) -> CompositeWindow {
+    let composite_step = match get_catalog_metadata(dataset_name, config).composite_step {
+        CompositeStep::Monthly => CompositeStep::Monthly,
+        CompositeStep::Annual => CompositeStep::Annual,
+        _ => CompositeStep::EightDay,
+    };
+    get_composite_window(row_date.year(), row_date.ordinal(), composite_step)
+}
+
+// second derivatives of a natural cubic spline through the anchor centers
+fn spline_second_derivatives(anchors: &[Anchor]) -> Vec<f64> {
+    let n = anchors.len();
+    let mut second_derivatives = vec![0.0; n];
+    if n < 3 {
+        return second_derivatives;
+    }
+    // tridiagonal system solved with the thomas algorithm, ends are fixed at 0
+    let mut u = vec![0.0; n];
+    for i in 1..n - 1 {
+        let h0 = anchors[i].center - anchors[i - 1].center;
+        let h1 = anchors[i + 1].center - anchors[i].center;
+        let sig = h0 / (h0 + h1);
+        let p = sig * second_derivatives[i - 1] + 2.0;
+        second_derivatives[i] = (sig - 1.0) / p;
+        let slope_diff = (anchors[i + 1].value - anchors[i].value) / h1
+            - (anchors[i].value - anchors[i - 1].value) / h0;
+        u[i] = (6.0 * slope_diff / (h0 + h1) - sig * u[i - 1]) / p;
+    }
+    for i in (1..n - 1).rev() {
+        second_derivatives[i] = second_derivatives[i] * second_derivatives[i + 1] + u[i];
+    }
+    second_derivatives
+}
+
+// interpolate one dataset onto every day. returns (value, observed) per day,
+// observed meaning the day falls inside the window of a composite that passed the goodpix threshold.
+// a composite filling several rows (16 day, monthly, annual) is one anchor at the center of its own window
+fn interpolate_dataset(
+    dataset_index: usize,
+    rows: &[CompositeRow],
+    n_days: i64,
+    config: &Config,
+    method: DailyInterpolation,
+) -> Vec<(Option<f64>, bool)> {
+    let first_day = rows[0].0;
+    let mut anchors: Vec<Anchor> = vec![];
+    let mut anchored_windows: Vec<CompositeWindow> = vec![];
+    for (_, values) in rows {
+        if let (Some(value), Some(goodpix), window) = values[dataset_index] {
+            if goodpix < config.daily_min_goodpix || anchored_windows.contains(&window) {
+                continue;
+            }
+            anchored_windows.push(window);
+            let start = (window.0 - first_day).num_days() as f64;
+            let end = (window.1 - first_day).num_days() as f64;
+            anchors.push(Anchor {
+                start,
+                end,
+                center: (start + end) / 2.0,
+                value,
+                goodpix,
+            });
+        }
+    }
+
+    // a combined terra/aqua row can cover more days than the next one, the interpolation needs the centers in order.
+    // two composites can't share a center, the first one is kept
+    anchors.sort_by(|a, b| a.center.total_cmp(&b.center));
+    anchors.dedup_by(|anchor, kept| anchor.center == kept.center);
+
+    let second_derivatives = if method == DailyInterpolation::Spline {
+        spline_second_derivatives(&anchors)
+    } else {
+        vec![]
+    };
+
+    let mut daily_values = vec![];
+    for day in 0..n_days {
+        let x = day as f64;
+        // composite covering the day with the most good pixels, for overlapping 16 day windows
+        let covering = anchors
+            .iter()
+            .filter(|anchor| anchor.start <= x && x <= anchor.end)
+            .reduce(|best, anchor| {
+                if anchor.goodpix > best.goodpix {
+                    anchor
+                } else {
+                    best
+                }
+            });
+        let observed = covering.is_some();
+
+        let value = if anchors.is_empty() {
+            None
+        } else if method == DailyInterpolation::Step {
+            // the covering composite's value, otherwise carry the last composite forward
+            match covering {
+                Some(anchor) => Some(anchor.value),
+                None => anchors
+                    .iter()
+                    .rev()
+                    .find(|anchor| anchor.end < x)
+                    .map(|anchor| anchor.value),
+            }
+        } else if x <= anchors[0].center || x >= anchors[anchors.len() - 1].center {
+            // no extrapolation past the first and last composites, only their own windows are filled
+            covering.map(|anchor| anchor.value)
+        } else {
+            let i = anchors
+                .iter()
+                .position(|anchor| anchor.center >= x)
+                .unwrap();
+            let (a0, a1) = (&anchors[i - 1], &anchors[i]);
+            let h = a1.center - a0.center;
+            let t0 = (a1.center - x) / h;
+            let t1 = (x - a0.center) / h;
+            // the composite with more good pixels pulls the line towards it, goodpix 0 on both ends is a plain line
+            let (w0, w1) = (t0 * a0.goodpix as f64, t1 * a1.goodpix as f64);
+            let mut value = if w0 + w1 > 0.0 {
+                (w0 * a0.value + w1 * a1.value) / (w0 + w1)
+            } else {
+                t0 * a0.value + t1 * a1.value
+            };
+            if method == DailyInterpolation::Spline {
+                value += ((t0.powi(3) - t0) * second_derivatives[i - 1]
+                    + (t1.powi(3) - t1) * second_derivatives[i])
+                    * h
+                    * h
+                    / 6.0;
+            }
+            Some(value)
+        };
+        daily_values.push((value.map(|v| (v * 10000.0).round() / 10000.0), observed));
+    }
+    daily_values
+}
+
+// Write {site}_daily.csv with every dataset interpolated from the site's composite rows onto each day.
+// each dataset gets a value column named like its column in the wide outputs and an _observed column
+pub fn write_daily_output(
+    site_code: &str,
+    rows: &[CompositeRow],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let Some(method) = config.daily_interpolation else {
+        return Ok(());
+    };
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let first_day = rows[0].0;
+    let (last_row_date, _) = rows[rows.len() - 1];
+    let last_day = (last_row_date
+        + chrono::Duration::days(
+            composite_days(last_row_date.year(), last_row_date.ordinal()) as i64 - 1,
+        ))
+    .min(config.end_date);
+    let n_days = (last_day - first_day).num_days() + 1;
+
+    let dataset_names = get_daily_dataset_names(config);
+    let columns: Vec<Vec<(Option<f64>, bool)>> = (0..dataset_names.len())
+        .map(|i| interpolate_dataset(i, rows, n_days, config, method))
+        .collect();
+
+    let path = PathBuf::from(format!("{}/{}_daily.csv", config.output_dir, site_code));
+    let mut wtr = WriterBuilder::new().from_path(get_temp_path(&path))?;
+
+    let mut header = vec![
+        "site_code".to_string(),
+        "year".to_string(),
+        "doy".to_string(),
+    ];
+    for dataset_name in &dataset_names {
+        let column = get_dataset_column(dataset_name, config);
+        header.push(column.clone());
+        header.push(format!("{column}_observed"));
+    }
+    wtr.write_record(&header)?;
+
+    for day in 0..n_days {
+        let date = first_day + chrono::Duration::days(day);
+        let mut record = vec![
+            site_code.to_string(),
+            date.year().to_string(),
+            date.ordinal().to_string(),
+        ];
+        for column in &columns {
+            let (value, observed) = column[day as usize];
+            record.push(value.map(|v| v.to_string()).unwrap_or_default());
+            record.push(observed.to_string());
+        }
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    finish_temp_file(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchor(center: f64, value: f64) -> Anchor {
+        Anchor {
+            start: center,
+            end: center,
+            center,
+            value,
+            goodpix: 1.0,
+        }
+    }
+
+    fn date(doy: u32) -> NaiveDate {
+        NaiveDate::from_yo_opt(2010, doy).unwrap()
+    }
+
+    // (row doy, value, goodpix, window doys) of a row of one dataset
+    type TestRow = (u32, Option<f64>, f32, (u32, u32));
+
+    fn composite_rows(rows: &[TestRow]) -> Vec<CompositeRow> {
+        rows.iter()
+            .map(|(doy, value, goodpix, (start, end))| {
+                (
+                    date(*doy),
+                    vec![(*value, Some(*goodpix), (date(*start), date(*end)))],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn natural_spline_second_derivatives() {
+        // h = 1 through (0, 0), (1, 1), (2, 0): 4 M1 = 6 (-1 - 1)
+        let anchors = [anchor(0.0, 0.0), anchor(1.0, 1.0), anchor(2.0, 0.0)];
+        assert_eq!(spline_second_derivatives(&anchors), vec![0.0, -3.0, 0.0]);
+        let anchors: Vec<Anchor> = [0.0, 3.0, 4.0, 10.0]
+            .iter()
+            .map(|&x| anchor(x, 2.0 * x - 1.0))
+            .collect();
+        for second_derivative in spline_second_derivatives(&anchors) {
+            assert!(second_derivative.abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn linear_weights_the_composites_by_goodpix() {
+        let config: Config = toml::from_str("").unwrap();
+        // a composite filling two rows is one anchor at the center of its window
+        let rows = composite_rows(&[
+            (1, Some(0.2), 1.0, (1, 15)),
+            (9, Some(0.2), 1.0, (1, 15)),
+            (17, Some(0.8), 0.5, (17, 31)),
+            (25, Some(0.8), 0.5, (17, 31)),
+        ]);
+        let daily = interpolate_dataset(0, &rows, 31, &config, DailyInterpolation::Linear);
+        // the window centers are days 7 and 23, their own windows are filled up to the ends
+        assert_eq!(daily[0], (Some(0.2), true));
+        assert_eq!(daily[30], (Some(0.8), true));
+        // halfway the weights are 0.5 * 1.0 and 0.5 * 0.5, the day between the windows isn't observed
+        assert_eq!(daily[15], (Some(0.4), false));
+        // a quarter of the way the weights are 0.75 * 1.0 and 0.25 * 0.5
+        assert_eq!(daily[11], (Some(0.2857), true));
+    }
+
+    #[test]
+    fn step_carries_the_last_composite_over_gaps() {
+        let config: Config = toml::from_str("daily_min_goodpix = 0.3").unwrap();
+        let rows = composite_rows(&[
+            (1, Some(0.2), 1.0, (1, 8)),
+            (9, None, 0.0, (9, 16)),
+            (17, Some(0.9), 0.1, (17, 24)),
+            (25, Some(0.6), 0.8, (25, 32)),
+        ]);
+        let daily = interpolate_dataset(0, &rows, 32, &config, DailyInterpolation::Step);
+        assert_eq!(daily[3], (Some(0.2), true));
+        assert_eq!(daily[10], (Some(0.2), false));
+        // below daily_min_goodpix, the day isn't observed either
+        assert_eq!(daily[18], (Some(0.2), false));
+        assert_eq!(daily[30], (Some(0.6), true));
+    }
+
+    #[test]
+    fn daily_columns_follow_the_wide_outputs() {
+        let config: Config = toml::from_str(
+            "derived_indices = true\n\
+             [[datasets]]\n\
+             name = \"Tree_Cover\"\n\
+             column = \"tree_cover\"",
+        )
+        .unwrap();
+        let dataset_names = get_daily_dataset_names(&config);
+        assert!(dataset_names.contains(&"NDWI".to_string()));
+        assert!(!dataset_names.contains(&"band_math".to_string()));
+        assert_eq!(dataset_names.last().unwrap(), "Tree_Cover");
+        let columns: Vec<String> = dataset_names
+            .iter()
+            .map(|dataset_name| get_dataset_column(dataset_name, &config))
+            .collect();
+        assert!(columns.contains(&"gpp_modis".to_string()));
+        assert!(columns.contains(&"nadir_ref_band1".to_string()));
+        assert!(columns.contains(&"tree_cover".to_string()));
+    }
+}
@@ -1,9 +1,20 @@
-use crate::data::DatasetMetadata;
+use crate::config::{Config, EtUnits, LeUnits};
+use crate::data::{CompositeStep, CompositeWindow, DatasetMetadata, Sensor};
+use chrono::{Datelike, Duration, Months, NaiveDate};
 
 pub fn get_dataset_names() -> Vec<String> {
     vec![
         "Lai".to_string(),
         "Fpar".to_string(),
+        "Gpp".to_string(),
+        "PsnNet".to_string(),
+        "Gpp_MOD17A2H".to_string(),
+        "PsnNet_MOD17A2H".to_string(),
+        "Npp".to_string(),
+        "ET".to_string(),
+        "LE".to_string(),
+        "PET".to_string(),
+        "PLE".to_string(),
         "EVI".to_string(),
         "NDVI".to_string(),
         "LST_Day".to_string(),
@@ -15,6 +26,133 @@ pub fn get_dataset_names() -> Vec<String> {
         "Nadir_Reflectance_Band5".to_string(),
         "Nadir_Reflectance_Band6".to_string(),
         "Nadir_Reflectance_Band7".to_string(),
+        "sur_refl_b01".to_string(),
+        "sur_refl_b02".to_string(),
+        "sur_refl_b03".to_string(),
+        "sur_refl_b04".to_string(),
+        "sur_refl_b05".to_string(),
+        "sur_refl_b06".to_string(),
+        "sur_refl_b07".to_string(),
+        "Albedo_BSA_shortwave".to_string(),
+        "Albedo_WSA_shortwave".to_string(),
+        "Albedo_BSA_vis".to_string(),
+        "Albedo_WSA_vis".to_string(),
+        "Albedo_BSA_nir".to_string(),
+        "Albedo_WSA_nir".to_string(),
+        "VNP_Lai".to_string(),
+        "VNP_Fpar".to_string(),
+        "VNP_NDVI".to_string(),
+        "VNP_EVI".to_string(),
+        "VNP_LST_Day".to_string(),
+        "VNP_LST_Night".to_string(),
+        "VNP_Nadir_Reflectance_I1".to_string(),
+        "VNP_Nadir_Reflectance_I2".to_string(),
+        "VNP_Nadir_Reflectance_I3".to_string(),
+    ]
+}
+
+// datasets only in the config catalog, read after the built in ones
+pub fn get_catalog_dataset_names(config: &Config) -> Vec<String> {
+    config
+        .datasets
+        .iter()
+        .filter(|dataset| get_dataset_metadata(&dataset.name).dataset.is_empty())
+        .map(|dataset| dataset.name.clone())
+        .collect()
+}
+
+// Output column of a dataset, its value column and the start of its _goodpix, _source, _smoothed and _filled columns.
+// a [[datasets]] table can name the column, otherwise it's the dataset name in lower case
+pub fn get_dataset_column(dataset_name: &str, config: &Config) -> String {
+    if let Some(column) = config
+        .datasets
+        .iter()
+        .find(|dataset| dataset.name == dataset_name)
+        .and_then(|dataset| dataset.column.clone())
+    {
+        return column;
+    }
+    match dataset_name {
+        "Gpp" => "gpp_modis".to_string(),
+        "PsnNet" => "psn_net".to_string(),
+        "Gpp_MOD17A2H" => "gpp_modis_mod17a2h".to_string(),
+        "PsnNet_MOD17A2H" => "psn_net_mod17a2h".to_string(),
+        "ET" => "et_modis".to_string(),
+        "LE" => "le_modis".to_string(),
+        "composite_day_of_the_year" => "vi_composite_doy".to_string(),
+        "Day_view_time" => "lst_day_view_time".to_string(),
+        "Day_view_angl" => "lst_day_view_angle".to_string(),
+        "Night_view_time" => "lst_night_view_time".to_string(),
+        "Night_view_angl" => "lst_night_view_angle".to_string(),
+        // nadir_ref_band1, vnp_nadir_ref_i1
+        _ => dataset_name
+            .to_lowercase()
+            .replace("_reflectance_", "_ref_"),
+    }
+}
+
+// layers describing when and from what angle the pixels of a composite were observed.
+// only extracted when extract_acquisition_layers is set in the config
+pub fn get_acquisition_layer_names() -> Vec<String> {
+    vec![
+        "composite_day_of_the_year".to_string(),
+        "Day_view_time".to_string(),
+        "Day_view_angl".to_string(),
+        "Night_view_time".to_string(),
+        "Night_view_angl".to_string(),
+    ]
+}
+
+// land cover datasets are categorical, so they aren't averaged like the ones above.
+// each one gets a majority class, tower class fraction and shannon diversity column instead.
+pub fn get_land_cover_names() -> Vec<String> {
+    vec![
+        "LC_Type1".to_string(),
+        "LC_Type2".to_string(),
+        "LC_Type3".to_string(),
+    ]
+}
+
+// the MOD09A1 state layer is a set of bit flags rather than a measurement,
+// it is turned into the fraction of the window that is cloudy, cloud shadowed or snow covered.
+pub fn get_state_flag_names() -> Vec<String> {
+    vec!["sur_refl_state_500m".to_string()]
+}
+
+// vegetation datasets that get smoothed and gap filled columns when smoothing is set in the config
+pub fn get_smoothed_dataset_names() -> Vec<String> {
+    vec![
+        "Lai".to_string(),
+        "Fpar".to_string(),
+        "EVI".to_string(),
+        "NDVI".to_string(),
+    ]
+}
+
+// indices computed per pixel from the MCD43A4 nadir reflectance bands when derived_indices is set in the config.
+// b1 red, b2 nir, b3 blue, b4 green, b5 1240nm, b6 1640nm, b7 2130nm
+pub fn get_derived_index_expressions() -> Vec<(String, String)> {
+    vec![
+        ("NDWI".to_string(), "(b2 - b5) / (b2 + b5)".to_string()),
+        ("LSWI".to_string(), "(b2 - b6) / (b2 + b6)".to_string()),
+        ("NIRv".to_string(), "(b2 - b1) / (b2 + b1) * b2".to_string()),
+        (
+            "kNDVI".to_string(),
+            "tanh(((b2 - b1) / (b2 + b1))^2)".to_string(),
+        ),
+        (
+            "EVI2".to_string(),
+            "2.5 * (b2 - b1) / (b2 + 2.4 * b1 + 1)".to_string(),
+        ),
+        ("NBR".to_string(), "(b2 - b7) / (b2 + b7)".to_string()),
+        (
+            "SAVI".to_string(),
+            "1.5 * (b2 - b1) / (b2 + b1 + 0.5)".to_string(),
+        ),
+        (
+            "MSAVI".to_string(),
+            "(2 * b2 + 1 - sqrt((2 * b2 + 1)^2 - 8 * (b2 - b1))) / 2".to_string(),
+        ),
     ]
 }
 
@@ -31,6 +169,161 @@ pub fn get_dataset_metadata(dataset_name: &str) -> DatasetMetadata {
             data_type: String::from("u8"),
             qc_type: String::from("u8"),
             scale_factor: if dataset_name == "Lai" { 0.1 } else { 0.01 },
+            units: String::from(if dataset_name == "Lai" { "m2/m2" } else { "1" }),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name == "composite_day_of_the_year" {
+        // the day each pixel of a 16 day VI composite was observed, used for temporal alignment
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD13A2".to_string(),
+            qc_name: "VI_Quality".to_string(),
+            data_bytes: 2,
+            qc_bytes: 2,
+            modis_size: String::from("1km"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u16"),
+            scale_factor: 1.0,
+            units: String::from("day of year"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::SixteenDay,
+            ..Default::default()
+        }
+    } else if dataset_name == "VNP_composite_day_of_the_year" {
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "VNP13A1".to_string(),
+            qc_name: "500_m_16_days_VI_Quality".to_string(),
+            data_bytes: 2,
+            qc_bytes: 2,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u16"),
+            scale_factor: 1.0,
+            units: String::from("day of year"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::SixteenDay,
+            ..Default::default()
+        }
+    } else if ["Day_view_time", "Night_view_time"].contains(&dataset_name) {
+        // local solar time of the observation in hours
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD11A2".to_string(),
+            qc_name: if dataset_name == "Day_view_time" {
+                "QC_Day".to_string()
+            } else {
+                "QC_Night".to_string()
+            },
+            data_bytes: 1,
+            qc_bytes: 1,
+            modis_size: String::from("1km"),
+            data_type: String::from("u8"),
+            qc_type: String::from("u8"),
+            scale_factor: 0.1,
+            units: String::from("hours"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if ["Day_view_angl", "Night_view_angl"].contains(&dataset_name) {
+        // stored as 0 - 130, the angle is -65 to 65 degrees
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD11A2".to_string(),
+            qc_name: if dataset_name == "Day_view_angl" {
+                "QC_Day".to_string()
+            } else {
+                "QC_Night".to_string()
+            },
+            data_bytes: 1,
+            qc_bytes: 1,
+            modis_size: String::from("1km"),
+            data_type: String::from("u8"),
+            qc_type: String::from("u8"),
+            scale_factor: 1.0,
+            units: String::from("degrees"),
+            add_offset: -65.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if ["Gpp", "PsnNet", "Gpp_MOD17A2H", "PsnNet_MOD17A2H"].contains(&dataset_name) {
+        // kgC/m^2 summed over the 8 day composite. MOD17A2HGF is reprocessed after the year with the cloudy
+        // MOD15A2H inputs gap filled, MOD17A2H is the version made as the composites come in and has the latest dates
+        let is_gap_filled = !dataset_name.ends_with("_MOD17A2H");
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: if is_gap_filled {
+                "MOD17A2HGF"
+            } else {
+                "MOD17A2H"
+            }
+            .to_string(),
+            qc_name: "Psn_QC_500m".to_string(),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 0.0001,
+            units: String::from("kgC/m2/8day"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name == "Npp" {
+        // annual product, kgC/m^2/year
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD17A3HGF".to_string(),
+            qc_name: "Npp_QC_500m".to_string(),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 0.0001,
+            units: String::from("kgC/m2/year"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::Annual,
+            ..Default::default()
+        }
+    } else if ["ET", "LE", "PET", "PLE"].contains(&dataset_name) {
+        // ET and PET are summed over the composite, LE and PLE are daily means
+        let is_water_flux = ["ET", "PET"].contains(&dataset_name);
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD16A2GF".to_string(),
+            qc_name: "ET_QC_500m".to_string(),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: if is_water_flux { 0.1 } else { 10000.0 },
+            units: String::from(if is_water_flux { "mm/8day" } else { "J/m2/day" }),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name.starts_with("LC_Type") {
+        // annual land cover classes. Type1 is IGBP, Type2 is UMD, Type3 is the LAI biome types
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MCD12Q1".to_string(),
+            qc_name: "QC".to_string(),
+            data_bytes: 1,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("u8"),
+            qc_type: String::from("u8"),
+            scale_factor: 1.0,
+            units: String::from("class"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::Annual,
+            ..Default::default()
         }
     } else if ["NDVI", "EVI"].contains(&dataset_name) {
         dataset_metadata = DatasetMetadata {
@@ -43,6 +336,10 @@ pub fn get_dataset_metadata(dataset_name: &str) -> DatasetMetadata {
             data_type: String::from("i16"),
             qc_type: String::from("u16"),
             scale_factor: 0.0001,
+            units: String::from("1"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::SixteenDay,
+            ..Default::default()
         }
     } else if ["LST_Day", "LST_Night"].contains(&dataset_name) {
         dataset_metadata = DatasetMetadata {
@@ -59,6 +356,155 @@ pub fn get_dataset_metadata(dataset_name: &str) -> DatasetMetadata {
             data_type: String::from("u16"),
             qc_type: String::from("u8"),
             scale_factor: 0.02,
+            units: String::from("K"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name.starts_with("sur_refl_") {
+        // sur_refl_qc_500m has the per band quality bits, the state layer is read on its own with it as qc
+        let is_state = dataset_name == "sur_refl_state_500m";
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD09A1".to_string(),
+            qc_name: "sur_refl_qc_500m".to_string(),
+            data_bytes: 2,
+            qc_bytes: 4,
+            modis_size: String::from("500m"),
+            data_type: String::from(if is_state { "u16" } else { "i16" }),
+            qc_type: String::from("u32"),
+            scale_factor: if is_state { 1.0 } else { 0.0001 },
+            units: String::from(if is_state { "flags" } else { "1" }),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name.starts_with("Albedo_") {
+        // Albedo_BSA_vis -> BRDF_Albedo_Band_Mandatory_Quality_vis
+        let band = dataset_name.rsplit('_').next().unwrap();
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MCD43A3".to_string(),
+            qc_name: format!("BRDF_Albedo_Band_Mandatory_Quality_{}", band),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 0.001,
+            units: String::from("1"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name == "Maximum_Snow_Extent" {
+        // classes, 200 is snow. MOD10A2 has no qc layer so the snow cover bit flags stand in for it
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MOD10A2".to_string(),
+            qc_name: "Eight_Day_Snow_Cover".to_string(),
+            data_bytes: 1,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("u8"),
+            qc_type: String::from("u8"),
+            scale_factor: 1.0,
+            units: String::from("class"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name == "Burn_Date" {
+        // monthly product. day of year of the burn, 0 unburned, -1 unmapped, -2 water
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "MCD64A1".to_string(),
+            qc_name: "QA".to_string(),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 1.0,
+            units: String::from("day of year"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::Monthly,
+            ..Default::default()
+        }
+    } else if ["VNP_Lai", "VNP_Fpar"].contains(&dataset_name) {
+        // VIIRS datasets, see get_viirs_comparison_pairs for the MODIS dataset each one lines up with
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "VNP15A2H".to_string(),
+            qc_name: "FparLai_QC".to_string(),
+            data_bytes: 1,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("u8"),
+            qc_type: String::from("u8"),
+            scale_factor: if dataset_name == "VNP_Lai" { 0.1 } else { 0.01 },
+            units: String::from(if dataset_name == "VNP_Lai" {
+                "m2/m2"
+            } else {
+                "1"
+            }),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if ["VNP_NDVI", "VNP_EVI"].contains(&dataset_name) {
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "VNP13A1".to_string(),
+            qc_name: "500_m_16_days_VI_Quality".to_string(),
+            data_bytes: 2,
+            qc_bytes: 2,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u16"),
+            scale_factor: 0.0001,
+            units: String::from("1"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::SixteenDay,
+            ..Default::default()
+        }
+    } else if ["VNP_LST_Day", "VNP_LST_Night"].contains(&dataset_name) {
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "VNP21A2".to_string(),
+            qc_name: if dataset_name == "VNP_LST_Day" {
+                "QC_Day".to_string()
+            } else {
+                "QC_Night".to_string()
+            },
+            data_bytes: 2,
+            qc_bytes: 2,
+            modis_size: String::from("1km"),
+            data_type: String::from("u16"),
+            qc_type: String::from("u16"),
+            scale_factor: 0.02,
+            units: String::from("K"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
+        }
+    } else if dataset_name.starts_with("VNP_Nadir") {
+        // the archive has the I bands on the same 500m grid as MCD43A4
+        let band: String = dataset_name.chars().rev().take(1).collect();
+        dataset_metadata = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            product: "VNP43IA4".to_string(),
+            qc_name: format!("BRDF_Albedo_Band_Mandatory_Quality_I{}", band),
+            data_bytes: 2,
+            qc_bytes: 1,
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 0.0001,
+            units: String::from("1"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
         }
     } else if dataset_name.contains("Nadir") {
         let band: String = dataset_name.chars().rev().take(1).collect();
@@ -72,7 +518,275 @@ pub fn get_dataset_metadata(dataset_name: &str) -> DatasetMetadata {
             data_type: String::from("i16"),
             qc_type: String::from("u8"),
             scale_factor: 0.0001,
+            units: String::from("1"),
+            add_offset: 0.0,
+            composite_step: CompositeStep::EightDay,
+            ..Default::default()
         }
     }
     dataset_metadata
 }
+
+// sample types read_mesh can decode, and the grids get_grid_size knows
+pub const SAMPLE_TYPES: [&str; 8] = ["u8", "i8", "u16", "i16", "u32", "i32", "f32", "f64"];
+pub const MODIS_SIZES: [&str; 3] = ["500m", "1km", "375m"];
+
+// bytes per sample of a data or qc type. load_config only lets through the types in SAMPLE_TYPES
+fn get_sample_bytes(sample_type: &str) -> u64 {
+    match sample_type {
+        "u8" | "i8" => 1,
+        "u16" | "i16" => 2,
+        "u32" | "i32" | "f32" => 4,
+        "f64" => 8,
+        _ => 0,
+    }
+}
+
+// Metadata of a dataset with its [[datasets]] table and composite_step from the config applied over the built in values.
+// datasets that are only in the config start as 500m i16 data with u8 qc. giving fill_values or good_qc_mask
+// replaces the fill and qc rules of the built in product
+pub fn get_catalog_metadata(dataset_name: &str, config: &Config) -> DatasetMetadata {
+    let mut dm = get_dataset_table_metadata(dataset_name, config);
+    if let Some(composite_step) = config.composite_step.get(dataset_name) {
+        dm.composite_step = *composite_step;
+    }
+    dm
+}
+
+fn get_dataset_table_metadata(dataset_name: &str, config: &Config) -> DatasetMetadata {
+    let mut dm = get_dataset_metadata(dataset_name);
+    let Some(dataset) = config
+        .datasets
+        .iter()
+        .find(|dataset| dataset.name == dataset_name)
+    else {
+        return dm;
+    };
+    if dm.dataset.is_empty() {
+        dm = DatasetMetadata {
+            dataset: dataset_name.to_string(),
+            modis_size: String::from("500m"),
+            data_type: String::from("i16"),
+            qc_type: String::from("u8"),
+            scale_factor: 1.0,
+            units: String::from("1"),
+            good_qc_mask: Some(u64::MAX),
+            ..Default::default()
+        };
+    }
+    if let Some(product) = &dataset.product {
+        dm.product = product.clone();
+    }
+    if let Some(data_layer) = &dataset.data_layer {
+        dm.data_layer = data_layer.clone();
+    }
+    if let Some(qc_name) = &dataset.qc_name {
+        dm.qc_name = qc_name.clone();
+    }
+    if let Some(modis_size) = &dataset.modis_size {
+        dm.modis_size = modis_size.clone();
+    }
+    if let Some(data_type) = &dataset.data_type {
+        dm.data_type = data_type.clone();
+    }
+    if let Some(qc_type) = &dataset.qc_type {
+        dm.qc_type = qc_type.clone();
+    }
+    if let Some(scale_factor) = dataset.scale_factor {
+        dm.scale_factor = scale_factor;
+    }
+    if let Some(add_offset) = dataset.add_offset {
+        dm.add_offset = add_offset;
+    }
+    if let Some(units) = &dataset.units {
+        dm.units = units.clone();
+    }
+    if dataset.fill_values.is_some() || dataset.good_qc_mask.is_some() {
+        dm.fill_values = dataset.fill_values.clone().unwrap_or_default();
+        dm.good_qc_mask = Some(dataset.good_qc_mask.unwrap_or(u64::MAX));
+    }
+    dm.data_bytes = get_sample_bytes(&dm.data_type);
+    dm.qc_bytes = get_sample_bytes(&dm.qc_type);
+    dm
+}
+
+// (pixels, lines) of the global grid a dataset is stored on, pixels along the x axis and lines along the y axis.
+// 500m is the most detailed MODIS grid, 1km has half as many pixels each way and 375m 4/3 as many
+pub fn get_grid_size(dm: &DatasetMetadata) -> (u64, u64) {
+    match dm.modis_size.as_str() {
+        "1km" => (43200, 21600),
+        "375m" => (115200, 57600),
+        _ => (86400, 43200),
+    }
+}
+
+// every dataset a run with this config reads, including the ones only used for flags, land cover and disturbance
+pub fn get_active_dataset_names(config: &Config) -> Vec<String> {
+    let mut dataset_names = get_dataset_names();
+    if config.extract_acquisition_layers {
+        dataset_names.extend(get_acquisition_layer_names());
+    }
+    dataset_names.extend(get_state_flag_names());
+    dataset_names.extend(get_land_cover_names());
+    dataset_names.push("Maximum_Snow_Extent".to_string());
+    dataset_names.push("Burn_Date".to_string());
+    dataset_names.extend(get_catalog_dataset_names(config));
+    dataset_names
+}
+
+// pixels along each side of the window read around the tower, about 3.5km across whatever the pixel size.
+// 9x9 375m pixels covers about the same area as the 7x7 500m windows
+pub fn get_window_size(dm: &DatasetMetadata) -> usize {
+    match dm.modis_size.as_str() {
+        "1km" => 3,
+        "375m" => 9,
+        _ => 7,
+    }
+}
+
+// Returns the metadata of a dataset for a particular sensor, or None if that sensor has no version of the product.
+// get_dataset_metadata always gives the terra (MOD) product, the aqua (MYD) and combined (MCD) products
+// share its layers, fill values and qc layout so only the product name changes.
+pub fn get_sensor_metadata(
+    dataset_name: &str,
+    sensor: Sensor,
+    config: &Config,
+) -> Option<DatasetMetadata> {
+    let mut dataset_metadata = get_catalog_metadata(dataset_name, config);
+    let has_counterpart = match sensor {
+        Sensor::Terra => true,
+        Sensor::Aqua => {
+            ["MOD15A2H", "MOD13A2", "MOD11A2"].contains(&dataset_metadata.product.as_str())
+        }
+        Sensor::Combined => dataset_metadata.product == "MOD15A2H",
+    };
+    if !has_counterpart {
+        return None;
+    }
+    dataset_metadata.product = dataset_metadata.product.replacen("MOD", sensor.code(), 1);
+    Some(dataset_metadata)
+}
+
+// version of the product in the archive directory and file names
+pub fn get_collection(product: &str) -> &'static str {
+    if product.starts_with("VNP") {
+        "002"
+    } else {
+        "061"
+    }
+}
+
+// (MODIS dataset, VIIRS dataset) pairs measuring the same thing, for the cross sensor comparison output.
+// the I bands are matched to the MODIS band with the closest wavelengths (red, nir, swir)
+pub fn get_viirs_comparison_pairs() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("Lai", "VNP_Lai"),
+        ("Fpar", "VNP_Fpar"),
+        ("NDVI", "VNP_NDVI"),
+        ("EVI", "VNP_EVI"),
+        ("LST_Day", "VNP_LST_Day"),
+        ("LST_Night", "VNP_LST_Night"),
+        ("Nadir_Reflectance_Band1", "VNP_Nadir_Reflectance_I1"),
+        ("Nadir_Reflectance_Band2", "VNP_Nadir_Reflectance_I2"),
+        ("Nadir_Reflectance_Band6", "VNP_Nadir_Reflectance_I3"),
+    ]
+}
+
+// The days of year output rows start on. rows always follow the 8 day MODIS grid (1, 9, .., 361) because the input
+// flux csv has one line per 8 days, composite_step only changes which composite of a dataset fills a row
+pub fn get_row_doys() -> impl Iterator<Item = u32> {
+    (1..=361).step_by(8)
+}
+
+// Days covered by a composite of the given step starting on doy. 16 day composites and 8 day rows are cut short
+// at the end of the year, monthly and annual ones cover their whole month or year
+pub fn get_composite_window(year: i32, doy: u32, composite_step: CompositeStep) -> CompositeWindow {
+    let start = NaiveDate::from_yo_opt(year, doy).unwrap();
+    let year_end = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
+    match composite_step {
+        CompositeStep::Daily => (start, start),
+        CompositeStep::EightDay => (
+            start,
+            start + Duration::days(composite_days(year, doy) as i64 - 1),
+        ),
+        CompositeStep::SixteenDay => (start, (start + Duration::days(15)).min(year_end)),
+        CompositeStep::Monthly => {
+            let month_start = start.with_day(1).unwrap();
+            let next_month = month_start.checked_add_months(Months::new(1)).unwrap();
+            (month_start, next_month.pred_opt().unwrap())
+        }
+        CompositeStep::Annual => (NaiveDate::from_yo_opt(year, 1).unwrap(), year_end),
+    }
+}
+
+// number of days an 8 day composite starting on doy covers. the last one of the year is cut short
+// (5 days, or 6 in leap years)
+pub fn composite_days(year: i32, doy: u32) -> u32 {
+    let days_in_year = NaiveDate::from_ymd_opt(year, 12, 31).unwrap().ordinal();
+    8.min(days_in_year - doy + 1)
+}
+
+// Change the scale factor and units of a dataset so its values come out in the units picked in the config.
+// only the MOD16A2GF datasets have options, everything else is left as is.
+pub fn apply_unit_conversion(dm: &mut DatasetMetadata, year: i32, doy: u32, config: &Config) {
+    if ["ET", "PET"].contains(&dm.dataset.as_str()) && config.et_units == EtUnits::MmPerDay {
+        dm.scale_factor /= composite_days(year, doy) as f64;
+        dm.units = String::from("mm/day");
+    } else if ["LE", "PLE"].contains(&dm.dataset.as_str()) {
+        match config.le_units {
+            LeUnits::JPerM2PerDay => {}
+            LeUnits::MjPerM2PerDay => {
+                dm.scale_factor /= 1_000_000.0;
+                dm.units = String::from("MJ/m2/day");
+            }
+            LeUnits::WPerM2 => {
+                dm.scale_factor /= 86400.0;
+                dm.units = String::from("W/m2");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn composite_windows() {
+        assert_eq!(get_row_doys().count(), 46);
+        assert_eq!(get_row_doys().last(), Some(361));
+        assert_eq!(composite_days(2010, 353), 8);
+        assert_eq!(composite_days(2010, 361), 5);
+        assert_eq!(composite_days(2012, 361), 6);
+        assert_eq!(
+            get_composite_window(2010, 9, CompositeStep::Daily),
+            (date(2010, 1, 9), date(2010, 1, 9))
+        );
+        assert_eq!(
+            get_composite_window(2012, 361, CompositeStep::EightDay),
+            (date(2012, 12, 26), date(2012, 12, 31))
+        );
+        assert_eq!(
+            get_composite_window(2010, 17, CompositeStep::SixteenDay),
+            (date(2010, 1, 17), date(2010, 2, 1))
+        );
+        // the last 16 day composite stops at the end of the year
+        assert_eq!(
+            get_composite_window(2010, 353, CompositeStep::SixteenDay),
+            (date(2010, 12, 19), date(2010, 12, 31))
+        );
+        // doy 57 of a leap year is feb 26
+        assert_eq!(
+            get_composite_window(2012, 57, CompositeStep::Monthly),
+            (date(2012, 2, 1), date(2012, 2, 29))
+        );
+        assert_eq!(
+            get_composite_window(2010, 185, CompositeStep::Annual),
+            (date(2010, 1, 1), date(2010, 12, 31))
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::{try_read_mesh, Rasters};
+use crate::scripts::output_columns::get_statistic_column;
+use crate::scripts::run::check_if_modis_data_exists;
+use chrono::{Datelike, Duration, NaiveDate};
+
+// MOD10A2 Maximum_Snow_Extent classes
+const SNOW: f64 = 200.0;
+// no snow, lake, ocean and lake ice. everything else (cloud, night, missing, fill) isn't an observation
+const SNOW_FREE: [f64; 4] = [25.0, 37.0, 39.0, 100.0];
+
+// snow fraction, burned and days since burn columns
+pub fn get_disturbance_columns(config: &Config) -> Vec<ColumnDescriptor> {
+    let snow_dm = get_catalog_metadata("Maximum_Snow_Extent", config);
+    let burn_dm = get_catalog_metadata("Burn_Date", config);
+    vec![
+        get_statistic_column("snow_fraction", ColumnType::Float32, &snow_dm, "1"),
+        get_statistic_column("burned", ColumnType::Boolean, &burn_dm, "1"),
+        get_statistic_column("days_since_burn", ColumnType::Int64, &burn_dm, "days"),
+    ]
+}
+
+// Reads the maximum snow extent around the tower and writes the fraction of observed pixels that were snow.
+pub fn get_snow_data<'a>(
+    mut rcrd: NewRecord<'a>,
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> NewRecord<'a> {
+    let tower_entry_data = TowerEntryData {
+        year,
+        doy,
+        lat: rcrd.lat,
+        lon: rcrd.lon,
+    };
+    let dm = get_catalog_metadata("Maximum_Snow_Extent", config);
+
+    let snow_fraction =
+        match check_if_modis_data_exists(year, doy, &config.modis_dir, &dm, &mut rasters.lookups) {
+            Ok(data_qc_paths) => try_read_mesh(&dm, &tower_entry_data, data_qc_paths, rasters)
+                .and_then(|(classes, _qc)| find_snow_fraction(&classes)),
+            Err(_e) => None,
+        };
+    rcrd.set_value("snow_fraction", snow_fraction.map(ColumnValue::Float32));
+    rcrd
+}
+
+// fraction of the observed pixels of the window that were snow, None when none were observed
+fn find_snow_fraction(classes: &[f64]) -> Option<f32> {
+    let snow_count = classes.iter().filter(|&&x| x == SNOW).count();
+    let observed_count = classes
+        .iter()
+        .filter(|&&x| x == SNOW || SNOW_FREE.contains(&x))
+        .count();
+    if observed_count == 0 {
+        None
+    } else {
+        Some(snow_count as f32 / observed_count as f32)
+    }
+}
+
+// For a row that isn't read again (from a checkpoint or kept by update): carries last_burn_date over the row,
+// the latest of the burns before it and the burn the row saw itself, and works out its days since burn from that
+pub fn carry_burn_date(rcrd: &mut NewRecord, last_burn_date: &mut Option<NaiveDate>) {
+    let composite_start = NaiveDate::from_yo_opt(rcrd.year, rcrd.doy).unwrap();
+    let composite_end =
+        composite_start + Duration::days(composite_days(rcrd.year, rcrd.doy) as i64 - 1);
+    let row_burn_date = rcrd
+        .get_f64("days_since_burn")
+        .map(|days| composite_end - Duration::days(days as i64));
+    *last_burn_date = (*last_burn_date).max(row_burn_date);
+    rcrd.set_value(
+        "days_since_burn",
+        last_burn_date.map(|last| ColumnValue::Int64((composite_end - last).num_days())),
+    );
+}
+
+// Reads the MCD64A1 burn dates around the tower for the months overlapping the composite.
+// writes whether any pixel burned during the composite, and the days from the last burn to the end of the composite.
+// last_burn_date is carried between calls so it has to be called in date order for each site, burns
+// before the first row of the site aren't known about.
+pub fn get_burn_data<'a>(
+    mut rcrd: NewRecord<'a>,
+    year: i32,
+    doy: u32,
+    last_burn_date: &mut Option<NaiveDate>,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> NewRecord<'a> {
+    let tower_entry_data = TowerEntryData {
+        year,
+        doy,
+        lat: rcrd.lat,
+        lon: rcrd.lon,
+    };
+    let dm = get_catalog_metadata("Burn_Date", config);
+
+    let composite_start = NaiveDate::from_yo_opt(year, doy).unwrap();
+    let composite_end = composite_start + Duration::days(composite_days(year, doy) as i64 - 1);
+
+    // a composite can cross into the next month, in which case both monthly files are read
+    let mut months = vec![composite_start.with_day(1).unwrap()];
+    if composite_end.month() != composite_start.month() {
+        months.push(composite_end.with_day(1).unwrap());
+    }
+
+    let mut any_month_found = false;
+    let mut burned = false;
+    for month in months {
+        let data_qc_paths = match check_if_modis_data_exists(
+            year,
+            month.ordinal(),
+            &config.modis_dir,
+            &dm,
+            &mut rasters.lookups,
+        ) {
+            Ok(data_qc_paths) => data_qc_paths,
+            Err(_e) => continue,
+        };
+        any_month_found = true;
+        let Some((burn_doys, _qc)) = try_read_mesh(&dm, &tower_entry_data, data_qc_paths, rasters)
+        else {
+            continue;
+        };
+        for burn_doy in burn_doys.iter().filter(|&&x| x > 0.0) {
+            let burn_date = match NaiveDate::from_yo_opt(year, *burn_doy as u32) {
+                Some(burn_date) => burn_date,
+                None => continue,
+            };
+            // burns later in the month than the composite are picked up by a later row
+            if burn_date > composite_end {
+                continue;
+            }
+            if burn_date >= composite_start {
+                burned = true;
+            }
+            if last_burn_date.is_none_or(|last| burn_date > last) {
+                *last_burn_date = Some(burn_date);
+            }
+        }
+    }
+
+    rcrd.set_value(
+        "burned",
+        any_month_found.then_some(ColumnValue::Boolean(burned)),
+    );
+    rcrd.set_value(
+        "days_since_burn",
+        last_burn_date.map(|last| ColumnValue::Int64((composite_end - last).num_days())),
+    );
+    rcrd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn carry(
+        year: i32,
+        doy: u32,
+        days_since_burn: Option<i64>,
+        last: &mut Option<NaiveDate>,
+    ) -> Option<f64> {
+        let mut rcrd = NewRecord {
+            year,
+            doy,
+            ..Default::default()
+        };
+        rcrd.set_value("days_since_burn", days_since_burn.map(ColumnValue::Int64));
+        carry_burn_date(&mut rcrd, last);
+        rcrd.get_f64("days_since_burn")
+    }
+
+    #[test]
+    fn burn_dates_carry_over_rows() {
+        let mut last_burn_date = None;
+        assert_eq!(carry(2010, 1, None, &mut last_burn_date), None);
+        // the row ends on jan 16, so the burn was on jan 13
+        assert_eq!(carry(2010, 9, Some(3), &mut last_burn_date), Some(3.0));
+        assert_eq!(last_burn_date, NaiveDate::from_ymd_opt(2010, 1, 13));
+        assert_eq!(carry(2010, 17, None, &mut last_burn_date), Some(11.0));
+        // a later burn takes over
+        assert_eq!(carry(2010, 25, Some(2), &mut last_burn_date), Some(2.0));
+        assert_eq!(last_burn_date, NaiveDate::from_ymd_opt(2010, 1, 30));
+        // the short last row ends on dec 31 and the count goes on into the next year
+        assert_eq!(carry(2010, 361, None, &mut last_burn_date), Some(335.0));
+        assert_eq!(carry(2011, 1, None, &mut last_burn_date), Some(343.0));
+    }
+
+    #[test]
+    fn snow_fraction_of_observed_pixels() {
+        // 1 (night) and 50 (cloud) aren't observations
+        assert_eq!(
+            find_snow_fraction(&[200.0, 200.0, 25.0, 1.0, 37.0, 50.0]),
+            Some(0.5)
+        );
+        assert_eq!(find_snow_fraction(&[1.0, 50.0, 255.0]), None);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::get_window_size;
+use crate::scripts::get_modis_data::get_window_center;
+use crate::scripts::provenance::get_file_info;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// how read_mesh picks and decodes the pixels of a window. goes in every key, so windows cached by an earlier version
+// are read again once it changes. raise it with any change to the window position, window size or sample decoding
+const WINDOW_RULE_VERSION: u32 = 1;
+
+// size and modification time of a raster, None when it doesn't exist
+type FileStamp = (Option<u64>, Option<String>);
+
+// the cache of extraction_cache in the config, opened by open_extraction_cache and passed to the readers in Rasters.
+// a sqlite database of the raw data and qc values of every window read, so towers close enough to share a window
+// and later runs with other flux files get them without reading the rasters. only the windows looked up are loaded
+pub struct ExtractionCache {
+    path: PathBuf,
+    connection: Connection,
+    // false once writing to it failed
+    writable: bool,
+    hits: usize,
+    reads: usize,
+}
+
+fn get_file_stamp(path: &Path) -> FileStamp {
+    let file_info = get_file_info(path, false);
+    (file_info.size, file_info.modified)
+}
+
+// Opens the cache database of the config, None when it doesn't set one
+pub fn open_extraction_cache(config: &Config) -> Result<Option<ExtractionCache>, Box<dyn Error>> {
+    let Some(cache_path) = &config.extraction_cache else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(cache_path);
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let connection =
+        Connection::open(&path).map_err(|e| format!("extraction cache {}: {e}", path.display()))?;
+    // a run that is stopped loses at most the last windows, the database itself stays whole
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS windows (
+            key TEXT PRIMARY KEY,
+            data_size INTEGER,
+            data_modified TEXT,
+            qc_size INTEGER,
+            qc_modified TEXT,
+            data BLOB NOT NULL,
+            qc BLOB NOT NULL
+        )",
+        [],
+    )?;
+    let windows: i64 =
+        connection.query_row("SELECT COUNT(*) FROM windows", [], |row| row.get(0))?;
+    println!("extraction cache: {windows} windows in {}", path.display());
+    Ok(Some(ExtractionCache {
+        path,
+        connection,
+        writable: true,
+        hits: 0,
+        reads: 0,
+    }))
+}
+
+// prints how many windows came from the cache
+pub fn close_extraction_cache(cache: Option<ExtractionCache>) {
+    if let Some(cache) = cache {
+        println!(
+            "extraction cache: {} of {} windows read from {}",
+            cache.hits,
+            cache.hits + cache.reads,
+            cache.path.display()
+        );
+    }
+}
+
+// The window of a dataset's data and qc files around a tower.
+// the file names have the product, collection, date and layer, the rest is where the window is and how its samples are decoded
+pub fn get_cache_key(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    data_qc_paths: &(PathBuf, PathBuf),
+) -> Option<String> {
+    let (seek_line, seek_pixel) = get_window_center(dm, tower_entry_data);
+    let window_size = get_window_size(dm);
+    Some(format!(
+        "{} {} {} {},{} {}x{} {} {} v{}",
+        data_qc_paths.0.file_name()?.to_string_lossy(),
+        data_qc_paths.1.file_name()?.to_string_lossy(),
+        dm.modis_size,
+        seek_line,
+        seek_pixel,
+        window_size,
+        window_size,
+        dm.data_type,
+        dm.qc_type,
+        WINDOW_RULE_VERSION
+    ))
+}
+
+// data and qc values cached for the window, None when they aren't or the data or qc file changed since
+pub fn get_cached_window(
+    cache: &mut ExtractionCache,
+    cache_key: &str,
+    data_qc_paths: &(PathBuf, PathBuf),
+) -> Option<(Vec<f64>, Vec<u64>)> {
+    let (data_size, data_modified) = get_file_stamp(&data_qc_paths.0);
+    let (qc_size, qc_modified) = get_file_stamp(&data_qc_paths.1);
+    let (data, qc): (Vec<u8>, Vec<u8>) = cache
+        .connection
+        .query_row(
+            "SELECT data, qc FROM windows WHERE key = ?1 AND data_size IS ?2 AND data_modified IS ?3
+                AND qc_size IS ?4 AND qc_modified IS ?5",
+            params![cache_key, data_size, data_modified, qc_size, qc_modified],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .ok()??;
+    cache.hits += 1;
+    Some((
+        data.chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect(),
+        qc.chunks_exact(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+// saves a window that was read, replacing the one of files that changed
+pub fn cache_window(
+    cache: &mut ExtractionCache,
+    cache_key: &str,
+    data_qc_paths: &(PathBuf, PathBuf),
+    (data, qc): (&[f64], &[u64]),
+) {
+    cache.reads += 1;
+    if !cache.writable {
+        return;
+    }
+    let (data_size, data_modified) = get_file_stamp(&data_qc_paths.0);
+    let (qc_size, qc_modified) = get_file_stamp(&data_qc_paths.1);
+    let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let qc: Vec<u8> = qc.iter().flat_map(|x| x.to_le_bytes()).collect();
+    if let Err(e) = cache.connection.execute(
+        "INSERT OR REPLACE INTO windows (key, data_size, data_modified, qc_size, qc_modified, data, qc)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![cache_key, data_size, data_modified, qc_size, qc_modified, data, qc],
+    ) {
+        println!(
+            "extraction cache: couldn't write to {}, {e}. windows read from now on aren't saved",
+            cache.path.display()
+        );
+        cache.writable = false;
+    }
+}
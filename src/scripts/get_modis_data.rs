@@ -1,39 +1,129 @@
-use bytemuck::cast_slice;
+use bytemuck::{pod_read_unaligned, Pod};
 // use rayon::prelude::*;
 use std::{
     fs::File,
+    io,
     io::{BufReader, Read, Seek, SeekFrom},
     path::PathBuf,
 };
 
+use crate::config::Config;
 use crate::data::*;
+use crate::scripts::define_metadata::{get_dataset_column, get_grid_size, get_window_size};
+use crate::scripts::extraction_cache::*;
+use crate::scripts::provenance::RasterLookups;
 
-fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QCH: usize>(
+// reads a flat byte buffer as a vec of T. the buffer comes from a u8 array so it isn't guaranteed
+// to be aligned for T, which is why this doesn't just use bytemuck::cast_slice.
+fn read_samples<T: Pod>(bytes: &[u8]) -> Vec<T> {
+    bytes
+        .chunks_exact(std::mem::size_of::<T>())
+        .map(pod_read_unaligned)
+        .collect()
+}
+
+// converts the raw bytes of a data layer into f64 values according to its sample type
+fn decode_data_samples(bytes: &[u8], sample_type: &str) -> Result<Vec<f64>, String> {
+    let samples = match sample_type {
+        "u8" => bytes.iter().map(|&x| f64::from(x)).collect(),
+        "i8" => read_samples::<i8>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "u16" => read_samples::<u16>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "i16" => read_samples::<i16>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "u32" => read_samples::<u32>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "i32" => read_samples::<i32>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "f32" => read_samples::<f32>(bytes)
+            .into_iter()
+            .map(f64::from)
+            .collect(),
+        "f64" => read_samples::<f64>(bytes),
+        _ => return Err(format!("unsupported data type {sample_type}")),
+    };
+    Ok(samples)
+}
+
+// converts the raw bytes of a qc layer into u64 values according to its sample type.
+// qc values are bit flags, so signed types keep their bit pattern instead of being sign extended.
+fn decode_qc_samples(bytes: &[u8], sample_type: &str) -> Result<Vec<u64>, String> {
+    let samples = match sample_type {
+        "u8" => bytes.iter().map(|&x| u64::from(x)).collect(),
+        "i8" => bytes.iter().map(|&x| u64::from(x)).collect(),
+        "u16" => read_samples::<u16>(bytes)
+            .into_iter()
+            .map(u64::from)
+            .collect(),
+        "i16" => read_samples::<i16>(bytes)
+            .into_iter()
+            .map(|x| u64::from(x as u16))
+            .collect(),
+        "u32" => read_samples::<u32>(bytes)
+            .into_iter()
+            .map(u64::from)
+            .collect(),
+        "i32" => read_samples::<i32>(bytes)
+            .into_iter()
+            .map(|x| u64::from(x as u32))
+            .collect(),
+        "f32" => read_samples::<f32>(bytes)
+            .into_iter()
+            .map(|x| x as u64)
+            .collect(),
+        "f64" => read_samples::<f64>(bytes)
+            .into_iter()
+            .map(|x| x as u64)
+            .collect(),
+        _ => return Err(format!("unsupported qc type {sample_type}")),
+    };
+    Ok(samples)
+}
+
+// (line, pixel) of the grid the tower is in, the center of the window read around it
+pub fn get_window_center(dm: &DatasetMetadata, tower_entry_data: &TowerEntryData) -> (u64, u64) {
+    let (_pixels, lines) = get_grid_size(dm);
+    // 1km and 500m are actually not accurate, each pixel actually represents a certain number of degrees squared on earth
+    // this finds that number.
+    // this doesn't really matter but the actual earth area the pixels represent change depending on the latitude as the pixels are mapped to degrees.
+    let pixel_size: f64 = 180.0 / lines as f64;
+    let seek_line: u64 = lines - ((tower_entry_data.lat + 90.0) / pixel_size) as u64;
+    let seek_pixel: u64 = ((tower_entry_data.lon + 180.0) / pixel_size) as u64;
+    (seek_line, seek_pixel)
+}
+
+// Reads the window of pixels around the tower from the data and qc files.
+// returns the unscaled data values and qc values of the window, row by row from the top left pixel,
+// or why they couldn't be read
+pub fn read_mesh(
     dm: &DatasetMetadata,
     tower_entry_data: &TowerEntryData,
     data_qc_paths: (PathBuf, PathBuf),
-    // generic array lengths (data width, data height, quality control width, qc height)
-    mut data_array: [[u8; DW]; DH],
-    mut qc_array: [[u8; QCW]; QCH],
-) -> (Option<f64>, Option<f32>) {
+) -> Result<(Vec<f64>, Vec<u64>), String> {
+    // think of these a matrix. data types with 1km pixels get a 3x3km array, 500km pixels get a 7x7 array of 500m pixels,
+    // the tower coordinates are located in the center pixel of each.
+    // rows are bytes, so a 16 bit data type has rows double the length and is converted from u8 after it is read.
+    let window_size = get_window_size(dm);
+    let mut data_array = vec![vec![0u8; window_size * dm.data_bytes as usize]; window_size];
+    let mut qc_array = vec![vec![0u8; window_size * dm.qc_bytes as usize]; window_size];
+
     // remote sensing jargon
     // think of the binary file as a 2d raster image
     // pixels is the number of pixels along the x axis
     // lines is the number of pixels along the y axis
-    // this is the resolution of the most detailed files (500m pixels)
-    let mut pixels = 86400;
-    let mut lines = 43200;
-
-    // if pixel size is 1km, divide these values by two to get an accurate measurement
-    if dm.modis_size == "1km" {
-        pixels = pixels / 2;
-        lines = lines / 2;
-    }
-    // 1km and 500m are actually not accurate, each pixel actually represents a certain number of degrees squared on earth
-    // this finds that number.
-    // this doesn't really matter but the actual earth area the pixels represent change depending on the latitude as the pixels are mapped to degrees.
-    let pixel_size: f64 = 180.0 / lines as f64;
-
+    // depends on the pixel size of the dataset, see get_grid_size
+    let (pixels, _lines) = get_grid_size(dm);
     // some calculations to find out which pixel the tower is located in for the data file and quality control file
     // out put is seek_point. which is the pixel minus half of the length of the width of the area we want to read (rounded up??? cant remember doesnt really matter)
     // so we can for example read 3 u8 pixels from left to right, with the tower being the middle pixel.
@@ -42,16 +132,19 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
     let qc_array_len = qc_array.len() as u64;
     let qc_matrix_width = qc_array[0].len() as i64;
 
-    let seek_line: u64 = lines - ((tower_entry_data.lat + 90.0) / pixel_size) as u64;
-    let seek_pixel: u64 = ((tower_entry_data.lon + 180.0) / pixel_size) as u64;
+    let (seek_line, seek_pixel) = get_window_center(dm, tower_entry_data);
     let data_seek_point: u64 =
         (((seek_line - 1) * pixels) + (seek_pixel - (data_array_len / 2))) * dm.data_bytes;
     let qc_seek_point: u64 =
         (((seek_line - 1) * pixels) + (seek_pixel - (qc_array_len / 2))) * dm.qc_bytes;
 
     // define files to read
-    let mut data_file = BufReader::new(File::open(data_qc_paths.0).unwrap());
-    let mut qc_file = BufReader::new(File::open(data_qc_paths.1).unwrap());
+    // a file that can't be opened or is shorter than the grid, e.g. a download that stopped part way, is an error
+    let (data_path, qc_path) = data_qc_paths;
+    let data_error = |e: io::Error| format!("{}: {e}", data_path.display());
+    let qc_error = |e: io::Error| format!("{}: {e}", qc_path.display());
+    let mut data_file = BufReader::new(File::open(&data_path).map_err(data_error)?);
+    let mut qc_file = BufReader::new(File::open(&qc_path).map_err(qc_error)?);
 
     //read data into arrays
     // if the matrix we are reading to is 7x7, the seek point is in the middle row
@@ -67,13 +160,13 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
                         + (pixels as i64 * dm.data_bytes as i64 * line_multiplier))
                         as u64,
                 ))
-                .unwrap();
+                .map_err(data_error)?;
         } else {
             data_file
                 .seek_relative((pixels as i64 * dm.data_bytes as i64) - data_matrix_width)
-                .unwrap();
+                .map_err(data_error)?;
         }
-        data_file.read_exact(array).unwrap();
+        data_file.read_exact(array).map_err(data_error)?;
     }
 
     // same for qc file
@@ -85,32 +178,86 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
                     (qc_seek_point as i64 + (pixels as i64 * dm.qc_bytes as i64 * line_multiplier))
                         as u64,
                 ))
-                .unwrap();
+                .map_err(qc_error)?;
         } else {
             qc_file
                 .seek_relative((pixels as i64 * dm.qc_bytes as i64) - qc_matrix_width)
-                .unwrap();
+                .map_err(qc_error)?;
         }
-        qc_file.read_exact(array).unwrap();
+        qc_file.read_exact(array).map_err(qc_error)?;
     }
 
-    // convert data and qc data to flat vec
+    // convert data and qc data to flat vecs of the sample type given in the metadata
     let flattened_qc_u8: Vec<u8> = qc_array.into_iter().flatten().collect();
     let flattened_data_u8: Vec<u8> = data_array.into_iter().flatten().collect();
-    let mut flattened_data_f64: Vec<f64> = vec![];
-    if dm.data_bytes == 2 {
-        if dm.data_type == "i16" {
-            let d: &[i16] = cast_slice(&flattened_data_u8);
-            flattened_data_f64 = d.iter().map(|item| item.clone() as f64).collect();
-        } else if dm.data_type == "u16" {
-            let d: &[u16] = cast_slice(&flattened_data_u8);
-            flattened_data_f64 = d.iter().map(|item| item.clone() as f64).collect();
+    Ok((
+        decode_data_samples(&flattened_data_u8, &dm.data_type)?,
+        decode_qc_samples(&flattened_qc_u8, &dm.qc_type)?,
+    ))
+}
+
+// read_mesh, with a window that can't be read printed and left empty like a missing file.
+// windows read before, by this run or an earlier one, come from the extraction cache when the config sets one
+pub fn try_read_mesh(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    data_qc_paths: (PathBuf, PathBuf),
+    rasters: &mut Rasters,
+) -> Option<(Vec<f64>, Vec<u64>)> {
+    let cache_key = rasters
+        .cache
+        .as_ref()
+        .and_then(|_| get_cache_key(dm, tower_entry_data, &data_qc_paths));
+    if let (Some(cache), Some(cache_key)) = (rasters.cache.as_mut(), &cache_key) {
+        if let Some(mesh) = get_cached_window(cache, cache_key, &data_qc_paths) {
+            return Some(mesh);
         }
-    } else if dm.data_bytes == 1 {
-        flattened_data_f64 = flattened_data_u8
-            .into_iter()
-            .map(|item| item as f64)
-            .collect();
+    }
+    match read_mesh(dm, tower_entry_data, data_qc_paths.clone()) {
+        Ok(mesh) => {
+            if let (Some(cache), Some(cache_key)) = (rasters.cache.as_mut(), &cache_key) {
+                cache_window(cache, cache_key, &data_qc_paths, (&mesh.0, &mesh.1));
+            }
+            Some(mesh)
+        }
+        Err(e) => {
+            println!(
+                "{} {} {} {}: {e}",
+                dm.product, dm.dataset, tower_entry_data.year, tower_entry_data.doy
+            );
+            None
+        }
+    }
+}
+
+// What the readers keep track of while a run reads the rasters, passed down with the config:
+// the lookups of the row being read for the provenance sidecar, and the extraction cache when the config sets one
+#[derive(Default)]
+pub struct Rasters {
+    pub lookups: RasterLookups,
+    pub cache: Option<ExtractionCache>,
+}
+
+// Reads the window around the tower for one dataset and returns the data average and good pixel percentage.
+// pixel_filter drops pixels of the window before averaging, see temporal_alignment and state_flags
+pub fn read_modis_values(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    data_qc_paths: (PathBuf, PathBuf),
+    // pixels of the window to keep, None keeps all of them
+    pixel_filter: Option<&[bool]>,
+    rasters: &mut Rasters,
+) -> (Option<f64>, Option<f32>) {
+    let Some((mut flattened_data_f64, mut flattened_qc)) =
+        try_read_mesh(dm, tower_entry_data, data_qc_paths, rasters)
+    else {
+        return (None, None);
+    };
+    if let Some(pixel_filter) = pixel_filter {
+        let mut keep = pixel_filter.iter();
+        flattened_data_f64.retain(|_| *keep.next().unwrap());
+        let mut keep = pixel_filter.iter();
+        flattened_qc.retain(|_| *keep.next().unwrap());
     }
 
     // Get count of null values in data vecs and good quality data from qc vecs
@@ -120,37 +267,96 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
     let data_len = flattened_data_f64.len() as f32;
     let mut null_val_count: f32 = 0.0;
     let mut good_qc_count = 0;
-    if dm.product == "MOD15A2H" {
-        null_val_count = flattened_data_f64.iter().filter(|&x| x >= &249.0).count() as f32;
-        flattened_data_f64.retain_mut(|&mut x| x < 249.0);
-        good_qc_count = flattened_qc_u8
+    // terra (MOD), aqua (MYD) and combined (MCD) versions (and VNP15A2H) of a product share fill values and qc layout
+    if let Some(good_qc_mask) = dm.good_qc_mask {
+        // datasets from the config catalog carry their own fill values and qc rule
+        null_val_count = flattened_data_f64
+            .iter()
+            .filter(|x| dm.fill_values.contains(x))
+            .count() as f32;
+        flattened_data_f64.retain_mut(|x| !dm.fill_values.contains(x));
+        good_qc_count = flattened_qc
             .iter()
-            .filter(|x| x.trailing_zeros() >= 1)
+            .filter(|x| *x & good_qc_mask == 0)
             .count();
-    } else if dm.product == "MOD13A2" {
+    } else if dm.dataset.contains("_view_") {
+        // view time and angle layers of MOD11A2 use 255 as fill instead of 0 like the LST layers
+        null_val_count = flattened_data_f64.iter().filter(|&x| x == &255.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != 255.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.dataset == "composite_day_of_the_year" {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x == &-1.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != -1.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.product.ends_with("15A2H") {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x >= &249.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x < 249.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b1 == 0).count();
+    } else if ["MOD17A2HGF", "MOD17A2H"].contains(&dm.product.as_str()) {
+        // 32761 - 32767 are all fill values (water, barren, urban, etc.)
+        null_val_count = flattened_data_f64.iter().filter(|&x| x >= &32761.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x < 32761.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b1 == 0).count();
+    } else if dm.product == "MOD17A3HGF" {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x >= &32761.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x < 32761.0);
+        // Npp_QC_500m is the percentage of unreliable 8 day inputs over the year, not a bit field
+        good_qc_count = flattened_qc.iter().filter(|x| **x < 50).count();
+    } else if dm.product == "MOD16A2GF" {
+        // 32761 - 32767 are all fill values (water, barren, urban, etc.)
+        null_val_count = flattened_data_f64.iter().filter(|&x| x >= &32761.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x < 32761.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b1 == 0).count();
+    } else if dm.product.ends_with("13A2") {
         null_val_count = flattened_data_f64.iter().filter(|&x| x == &-3000.0).count() as f32;
         flattened_data_f64.retain_mut(|&mut x| x != -3000.0);
-        let qc: &[u16] = cast_slice(&flattened_qc_u8);
-        good_qc_count = qc.iter().filter(|x| x.trailing_zeros() >= 2).count();
-    } else if dm.product == "MOD11A2" {
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.product.ends_with("11A2") {
         null_val_count = flattened_data_f64.iter().filter(|&x| x == &0.0).count() as f32;
         flattened_data_f64.retain_mut(|&mut x| x != 0.0);
-        good_qc_count = flattened_qc_u8
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.product == "MOD09A1" {
+        null_val_count = flattened_data_f64
+            .iter()
+            .filter(|&x| x == &-28672.0)
+            .count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != -28672.0);
+        // bits 0-1 are the overall quality, then 4 bits for each band starting at bit 2. all 0 is the best quality
+        let band: u32 = dm.dataset[dm.dataset.len() - 1..].parse().unwrap();
+        let band_shift = 2 + 4 * (band - 1);
+        good_qc_count = flattened_qc
             .iter()
-            .filter(|x| x.trailing_zeros() >= 2)
+            .filter(|x| *x & 0b11 == 0 && (*x >> band_shift) & 0b1111 == 0)
             .count();
+    } else if dm.product == "MCD43A3" {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x == &32767.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != 32767.0);
+        good_qc_count = flattened_qc.iter().filter(|x| **x == 0).count();
+    } else if dm.product == "VNP13A1" {
+        null_val_count = flattened_data_f64
+            .iter()
+            .filter(|&x| x == &-15000.0)
+            .count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != -15000.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.product == "VNP21A2" {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x == &0.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != 0.0);
+        good_qc_count = flattened_qc.iter().filter(|x| *x & 0b11 == 0).count();
+    } else if dm.product == "VNP43IA4" {
+        null_val_count = flattened_data_f64.iter().filter(|&x| x == &32767.0).count() as f32;
+        flattened_data_f64.retain_mut(|&mut x| x != 32767.0);
+        good_qc_count = flattened_qc.iter().filter(|x| **x == 0).count();
     } else if dm.product == "MCD43A4" {
         null_val_count = flattened_data_f64.iter().filter(|&x| x == &32767.0).count() as f32;
         flattened_data_f64.retain_mut(|&mut x| x != 32767.0);
-        good_qc_count = flattened_qc_u8
-            .into_iter()
-            .filter(|x| x.clone() == 0 as u8)
-            .count();
+        good_qc_count = flattened_qc.iter().filter(|x| **x == 0).count();
     }
 
     // if nulls are more than half of data, return empty string
     // not enough data to justify using
-    if (null_val_count / data_len as f32) > 0.5 {
+    // an empty vec would otherwise give a NaN mean
+    if (null_val_count / data_len) > 0.5 || flattened_data_f64.is_empty() {
         (None, None)
     } else {
         // Get goodpix percent
@@ -159,7 +365,7 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
         // apply scale factors
         flattened_data_f64 = flattened_data_f64
             .into_iter()
-            .map(|x| x * dm.scale_factor)
+            .map(|x| x * dm.scale_factor + dm.add_offset)
             .collect();
         let array_sum: f64 = flattened_data_f64.iter().sum();
         let array_mean = array_sum / flattened_data_f64.len() as f64;
@@ -170,149 +376,63 @@ fn find_mesh_values<const DW: usize, const DH: usize, const QCW: usize, const QC
     }
 }
 
-pub fn get_modis_data(
-    mut rcrd: NewRecord,
-    dm: DatasetMetadata,
-    data_qc_paths: (PathBuf, PathBuf),
-) -> NewRecord {
-    let tower_entry_data = TowerEntryData {
-        year: rcrd.year,
-        doy: rcrd.doy,
-        lat: rcrd.lat.parse().unwrap(),
-        lon: rcrd.lon.parse().unwrap(),
-    };
-
-    // Create nested arrays for each dataset. think of these a matrix. data types with 1km pixels get a 3x3km array
-    // 500km pixels get a 7x7 array of 500m pixels, the tower coordinates are located in the center pixel of each.
-    // some rows have double the length because their data type is 16 bit and I convert from u8 to 16 bit after I read it.
-    let array_lai: [[u8; 7]; 7] = Default::default();
-    let array_lai_qc: [[u8; 7]; 7] = Default::default();
-
-    let array_fpar: [[u8; 7]; 7] = Default::default();
-    let array_fpar_qc: [[u8; 7]; 7] = Default::default();
-
-    // Arrays are double in size and u8, will convert to 3x3 i16 later
-    let array_evi: [[u8; 6]; 3] = Default::default();
-    let array_ndvi: [[u8; 6]; 3] = Default::default();
-    let array_vi_qc: [[u8; 6]; 3] = Default::default();
-
-    let array_lst_day: [[u8; 6]; 3] = Default::default();
-    let array_lst_night: [[u8; 6]; 3] = Default::default();
-    let array_lst_qc: [[u8; 3]; 3] = Default::default();
-
-    let array_nrb: [[u8; 14]; 7] = Default::default();
-    let array_brdf: [[u8; 7]; 7] = Default::default();
+// writes the data average and good pixel percentage of a dataset to its columns in the csv record
+pub fn set_modis_values<'a>(
+    mut rcrd: NewRecord<'a>,
+    dataset: &str,
+    (data_ave, goodpix_per): (Option<f64>, Option<f32>),
+    config: &Config,
+) -> NewRecord<'a> {
+    let column = get_dataset_column(dataset, config);
+    rcrd.set_value(
+        &format!("{column}_goodpix"),
+        goodpix_per.map(ColumnValue::Float32),
+    );
+    rcrd.set_value(&column, data_ave.map(ColumnValue::Float64));
+    rcrd
+}
 
-    let data_ave;
-    let goodpix_per;
+// the reverse of set_modis_values, reads the data average and good pixel percentage of a dataset back out of the record
+pub fn get_modis_values(
+    rcrd: &NewRecord,
+    dataset: &str,
+    config: &Config,
+) -> (Option<f64>, Option<f32>) {
+    let column = get_dataset_column(dataset, config);
+    (
+        rcrd.get_f64(&column),
+        rcrd.get_f32(&format!("{column}_goodpix")),
+    )
+}
 
-    // couldn't figure out any other way to do this than replicate it for every dataset lol.
-    // calls above function find_mesh_value, which is when it reads the binary files.
-    // it then writes the data it reads and processes to the new csv record
-    if dm.dataset.as_str() == "Lai" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_lai,
-            array_lai_qc,
-        );
-        rcrd.lai = data_ave;
-        rcrd.lai_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str() == "Fpar" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_fpar,
-            array_fpar_qc,
-        );
-        rcrd.fpar = data_ave;
-        rcrd.fpar_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str() == "EVI" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_evi,
-            array_vi_qc,
-        );
-        rcrd.evi = data_ave;
-        rcrd.evi_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str() == "NDVI" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_ndvi,
-            array_vi_qc,
-        );
-        rcrd.ndvi = data_ave;
-        rcrd.ndvi_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str() == "LST_Day" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_lst_day,
-            array_lst_qc,
-        );
-        rcrd.lst_day = data_ave;
-        rcrd.lst_day_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str() == "LST_Night" {
-        (data_ave, goodpix_per) = find_mesh_values(
-            &dm,
-            &tower_entry_data,
-            data_qc_paths,
-            array_lst_night,
-            array_lst_qc,
-        );
-        rcrd.lst_night = data_ave;
-        rcrd.lst_night_goodpix = goodpix_per;
-        rcrd
-    } else if dm.dataset.as_str().contains("Nadir") {
-        (data_ave, goodpix_per) =
-            find_mesh_values(&dm, &tower_entry_data, data_qc_paths, array_nrb, array_brdf);
-        match dm.dataset.as_str() {
-            "Nadir_Reflectance_Band1" => {
-                rcrd.nadir_ref_band1 = data_ave;
-                rcrd.nadir_ref_band1_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band2" => {
-                rcrd.nadir_ref_band2 = data_ave;
-                rcrd.nadir_ref_band2_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band3" => {
-                rcrd.nadir_ref_band3 = data_ave;
-                rcrd.nadir_ref_band3_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band4" => {
-                rcrd.nadir_ref_band4 = data_ave;
-                rcrd.nadir_ref_band4_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band5" => {
-                rcrd.nadir_ref_band5 = data_ave;
-                rcrd.nadir_ref_band5_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band6" => {
-                rcrd.nadir_ref_band6 = data_ave;
-                rcrd.nadir_ref_band6_goodpix = goodpix_per;
-            }
-            "Nadir_Reflectance_Band7" => {
-                rcrd.nadir_ref_band7 = data_ave;
-                rcrd.nadir_ref_band7_goodpix = goodpix_per;
-            }
-            _ => {}
-        }
-        rcrd
-    } else {
-        rcrd
-    }
+// records which sensor the value of a dataset came from. only datasets with an aqua counterpart have a source column,
+// see get_column_descriptors
+pub fn set_modis_source<'a>(
+    mut rcrd: NewRecord<'a>,
+    dataset: &str,
+    source: Option<String>,
+    config: &Config,
+) -> NewRecord<'a> {
+    let column = get_dataset_column(dataset, config);
+    rcrd.set_value(&format!("{column}_source"), source.map(ColumnValue::Text));
+    rcrd
+}
 
-    // Ok(new_record?)
+// smoothed and gap filled values, only for the datasets in get_smoothed_dataset_names
+pub fn set_modis_smoothed<'a>(
+    mut rcrd: NewRecord<'a>,
+    dataset: &str,
+    (smoothed, filled): (Option<f64>, Option<f64>),
+    config: &Config,
+) -> NewRecord<'a> {
+    let column = get_dataset_column(dataset, config);
+    rcrd.set_value(
+        &format!("{column}_smoothed"),
+        smoothed.map(ColumnValue::Float64),
+    );
+    rcrd.set_value(
+        &format!("{column}_filled"),
+        filled.map(ColumnValue::Float64),
+    );
+    rcrd
 }
@@ -0,0 +1,218 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::checkpoint::write_atomic;
+use crate::scripts::combine_sensors::get_sensors;
+use crate::scripts::define_metadata::*;
+use crate::scripts::run::get_modis_paths;
+use chrono::prelude::*;
+use serde::Serialize;
+use std::{error::Error, fs, path::Path};
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FileStatus {
+    Present,
+    Missing,
+    // the file is there but isn't the size of the whole grid, e.g. a download that stopped part way
+    SizeMismatch,
+}
+
+#[derive(Serialize)]
+struct InventoryFile {
+    path: String,
+    status: FileStatus,
+    size: Option<u64>,
+    expected_size: u64,
+}
+
+// the data and qc file of one composite
+#[derive(Serialize)]
+struct InventoryEntry {
+    date: String,
+    data: InventoryFile,
+    qc: InventoryFile,
+}
+
+#[derive(Serialize)]
+struct DatasetInventory {
+    dataset: String,
+    product: String,
+    modis_size: String,
+    composite_step: CompositeStep,
+    // composites in start_date..=end_date, and how many have both files present with the right size
+    expected: usize,
+    complete: usize,
+    data_missing: usize,
+    data_size_mismatch: usize,
+    qc_missing: usize,
+    qc_size_mismatch: usize,
+    // why the files couldn't be named, the dataset isn't checked then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    files: Vec<InventoryEntry>,
+}
+
+#[derive(Serialize)]
+struct Inventory<'a> {
+    created: String,
+    modis_dir: &'a str,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    datasets: Vec<DatasetInventory>,
+}
+
+// (year, doy) of every composite of a dataset that covers part of start_date..=end_date.
+// aqua 16 day composites start 8 days after the terra ones. a composite starting before start_date still counts
+// when its window reaches it
+fn get_expected_dates(dm: &DatasetMetadata, config: &Config) -> Vec<(i32, u32)> {
+    let mut dates = vec![];
+    for year in config.start_date.year()..=config.end_date.year() {
+        let days_in_year = NaiveDate::from_ymd_opt(year, 12, 31).unwrap().ordinal();
+        let doys: Vec<u32> = match dm.composite_step {
+            CompositeStep::Daily => (1..=days_in_year).collect(),
+            CompositeStep::EightDay => (1..=days_in_year).step_by(8).collect(),
+            CompositeStep::SixteenDay => {
+                let first_doy = if dm.product.starts_with("MYD") { 9 } else { 1 };
+                (first_doy..=days_in_year).step_by(16).collect()
+            }
+            CompositeStep::Monthly => (1..=12)
+                .map(|month| NaiveDate::from_ymd_opt(year, month, 1).unwrap().ordinal())
+                .collect(),
+            CompositeStep::Annual => vec![1],
+        };
+        for doy in doys {
+            let (date, period_end) = get_composite_window(year, doy, dm.composite_step);
+            if date <= config.end_date && period_end >= config.start_date {
+                dates.push((year, doy));
+            }
+        }
+    }
+    dates
+}
+
+fn get_inventory_file(path: &Path, expected_size: u64) -> InventoryFile {
+    let size = fs::metadata(path).ok().map(|metadata| metadata.len());
+    let status = match size {
+        None => FileStatus::Missing,
+        Some(size) if size != expected_size => FileStatus::SizeMismatch,
+        Some(_) => FileStatus::Present,
+    };
+    InventoryFile {
+        path: path.display().to_string(),
+        status,
+        size,
+        expected_size,
+    }
+}
+
+fn get_dataset_inventory(dm: &DatasetMetadata, config: &Config) -> DatasetInventory {
+    let mut dataset_inventory = DatasetInventory {
+        dataset: dm.dataset.clone(),
+        product: dm.product.clone(),
+        modis_size: dm.modis_size.clone(),
+        composite_step: dm.composite_step,
+        expected: 0,
+        complete: 0,
+        data_missing: 0,
+        data_size_mismatch: 0,
+        qc_missing: 0,
+        qc_size_mismatch: 0,
+        error: None,
+        files: vec![],
+    };
+    // every file holds the whole grid, so anything else is a broken file
+    let (pixels, lines) = get_grid_size(dm);
+    for (year, doy) in get_expected_dates(dm, config) {
+        let (date, data_path, qc_path) = match get_modis_paths(year, doy, &config.modis_dir, dm) {
+            Ok(paths) => paths,
+            Err(e) => {
+                dataset_inventory.error = Some(e);
+                break;
+            }
+        };
+        let data = get_inventory_file(&data_path, pixels * lines * dm.data_bytes);
+        let qc = get_inventory_file(&qc_path, pixels * lines * dm.qc_bytes);
+        dataset_inventory.expected += 1;
+        match data.status {
+            FileStatus::Missing => dataset_inventory.data_missing += 1,
+            FileStatus::SizeMismatch => dataset_inventory.data_size_mismatch += 1,
+            FileStatus::Present => {}
+        }
+        match qc.status {
+            FileStatus::Missing => dataset_inventory.qc_missing += 1,
+            FileStatus::SizeMismatch => dataset_inventory.qc_size_mismatch += 1,
+            FileStatus::Present => {}
+        }
+        if data.status == FileStatus::Present && qc.status == FileStatus::Present {
+            dataset_inventory.complete += 1;
+        }
+        dataset_inventory
+            .files
+            .push(InventoryEntry { date, data, qc });
+    }
+    dataset_inventory
+}
+
+// `asia_flux_modis inventory [config.toml]`
+// Checks the data and qc file of every dataset the config reads, for every composite in start_date..=end_date and every sensor
+// picked by sensor_combination. prints a table of the counts and writes every file's status to {output_dir}/inventory.json.
+// the extraction itself just leaves missing composites empty, this is how gaps in the archive get noticed
+pub fn inventory(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut datasets: Vec<DatasetInventory> = vec![];
+    for dataset_name in get_active_dataset_names(config) {
+        for sensor in get_sensors(&dataset_name, config) {
+            let dm = match get_sensor_metadata(&dataset_name, sensor, config) {
+                Some(dm) => dm,
+                None => continue,
+            };
+            datasets.push(get_dataset_inventory(&dm, config));
+        }
+    }
+
+    println!(
+        "{:<45} {:<10} {:>8} {:>8} {:>12} {:>12} {:>10} {:>10}",
+        "dataset",
+        "product",
+        "expected",
+        "complete",
+        "data missing",
+        "data size",
+        "qc missing",
+        "qc size"
+    );
+    for d in &datasets {
+        if let Some(e) = d.error {
+            println!("{:<45} {:<10} {}", d.dataset, d.product, e);
+            continue;
+        }
+        println!(
+            "{:<45} {:<10} {:>8} {:>8} {:>12} {:>12} {:>10} {:>10}",
+            d.dataset,
+            d.product,
+            d.expected,
+            d.complete,
+            d.data_missing,
+            d.data_size_mismatch,
+            d.qc_missing,
+            d.qc_size_mismatch
+        );
+    }
+
+    if !Path::new(&config.output_dir).is_dir() {
+        fs::create_dir_all(&config.output_dir)?;
+    }
+    let inventory_path = Path::new(&config.output_dir).join("inventory.json");
+    let inventory = Inventory {
+        created: Utc::now().to_rfc3339(),
+        modis_dir: &config.modis_dir,
+        start_date: config.start_date,
+        end_date: config.end_date,
+        datasets,
+    };
+    write_atomic(
+        &inventory_path,
+        serde_json::to_string_pretty(&inventory)?.as_bytes(),
+    )?;
+    println!("wrote {}", inventory_path.display());
+    Ok(())
+}
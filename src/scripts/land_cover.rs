@@ -0,0 +1,141 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::{try_read_mesh, Rasters};
+use crate::scripts::output_columns::get_statistic_column;
+use crate::scripts::run::check_if_modis_data_exists;
+use std::collections::BTreeMap;
+
+// 255 is unclassified/fill in every MCD12Q1 layer
+const LAND_COVER_FILL: f64 = 255.0;
+
+// (majority class, fraction of the window in the tower pixel's class, shannon diversity index)
+type LandCoverComposition = (Option<u8>, Option<f32>, Option<f64>);
+
+// works out the composition of the window from a flat vec of class values.
+// the tower is in the middle pixel of the window.
+fn find_land_cover_composition(classes: &[f64]) -> LandCoverComposition {
+    let tower_class = classes[classes.len() / 2];
+
+    // count of pixels in each class, ignoring fill
+    let mut class_counts: BTreeMap<u8, usize> = BTreeMap::new();
+    for class in classes.iter().filter(|&&x| x != LAND_COVER_FILL) {
+        *class_counts.entry(*class as u8).or_default() += 1;
+    }
+    let total = class_counts.values().sum::<usize>() as f64;
+    if total == 0.0 {
+        return (None, None, None);
+    }
+
+    // ties go to the lower class number
+    let majority = class_counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| **count)
+        .map(|(class, _)| *class);
+
+    let tower_fraction = if tower_class == LAND_COVER_FILL {
+        None
+    } else {
+        let tower_count = class_counts.get(&(tower_class as u8)).unwrap();
+        Some(*tower_count as f32 / total as f32)
+    };
+
+    // H = -sum(p * ln(p)), 0 when the whole window is one class
+    let shannon: f64 = class_counts
+        .values()
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.ln()
+        })
+        .sum();
+    let shannon_round = (shannon * 10000.0).round() / 10000.0;
+
+    (majority, tower_fraction, Some(shannon_round))
+}
+
+// start of the column names of a land cover layer, after the classification it uses
+fn get_land_cover_prefix(dataset_name: &str) -> String {
+    match dataset_name {
+        "LC_Type1" => "lc_igbp".to_string(),
+        "LC_Type2" => "lc_umd".to_string(),
+        "LC_Type3" => "lc_lai".to_string(),
+        _ => dataset_name.to_lowercase(),
+    }
+}
+
+// majority class, tower fraction and shannon diversity columns of a land cover layer
+pub fn get_land_cover_columns(dataset_name: &str, config: &Config) -> Vec<ColumnDescriptor> {
+    let dm = get_catalog_metadata(dataset_name, config);
+    let prefix = get_land_cover_prefix(dataset_name);
+    vec![
+        get_statistic_column(
+            &format!("{prefix}_majority"),
+            ColumnType::UInt8,
+            &dm,
+            "class",
+        ),
+        get_statistic_column(
+            &format!("{prefix}_tower_fraction"),
+            ColumnType::Float32,
+            &dm,
+            "1",
+        ),
+        get_statistic_column(&format!("{prefix}_shannon"), ColumnType::Float64, &dm, "1"),
+    ]
+}
+
+// (column, value) of every land cover column for a year, the same for every row of the year
+pub type LandCoverValues = Vec<(String, Option<ColumnValue>)>;
+
+// Reads the MCD12Q1 land cover layers for the year around the tower and works out their composition.
+// land cover is annual, so this is called once for each year of a site and the values are set on each of its rows
+pub fn get_land_cover_values(
+    tower_entry_data: &TowerEntryData,
+    year: i32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> LandCoverValues {
+    let mut values = vec![];
+    for dataset_name in get_land_cover_names() {
+        let dm = get_catalog_metadata(&dataset_name, config);
+        let composition =
+            match check_if_modis_data_exists(year, 1, &config.modis_dir, &dm, &mut rasters.lookups)
+            {
+                Ok(data_qc_paths) => {
+                    match try_read_mesh(&dm, tower_entry_data, data_qc_paths, rasters) {
+                        Some((classes, _qc)) => find_land_cover_composition(&classes),
+                        None => (None, None, None),
+                    }
+                }
+                Err(_e) => (None, None, None),
+            };
+
+        let (majority, tower_fraction, shannon) = composition;
+        let prefix = get_land_cover_prefix(&dataset_name);
+        values.push((
+            format!("{prefix}_majority"),
+            majority.map(ColumnValue::UInt8),
+        ));
+        values.push((
+            format!("{prefix}_tower_fraction"),
+            tower_fraction.map(ColumnValue::Float32),
+        ));
+        values.push((
+            format!("{prefix}_shannon"),
+            shannon.map(ColumnValue::Float64),
+        ));
+    }
+    values
+}
+
+// writes the land cover composition of the row's year to the record
+pub fn set_land_cover_values<'a>(
+    mut rcrd: NewRecord<'a>,
+    land_cover_values: &LandCoverValues,
+) -> NewRecord<'a> {
+    for (column, value) in land_cover_values {
+        rcrd.set_value(column, value.clone());
+    }
+    rcrd
+}
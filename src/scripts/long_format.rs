@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::output_columns::{get_column_value, get_output_columns};
+use chrono::NaiveDate;
+use std::error::Error;
+
+// Turns a site's rows into one record per variable with a value.
+// every output column except the site, _goodpix and _source columns becomes a variable, so new datasets show up without
+// changing the layout. source is the _source column where there is one, otherwise the product or "tower" for the flux columns.
+// the smoothed and filled series have the product of their dataset, they mix the values of every sensor read
+pub fn get_long_records<'a>(
+    site_code: &'a str,
+    rows: &[NewRecord],
+    config: &Config,
+) -> Result<Vec<LongRecord<'a>>, Box<dyn Error>> {
+    let columns = get_output_columns(config)?;
+    // the goodpix and source columns of the dataset a value column is read from
+    let find_dataset_column = |column: &ColumnDescriptor, kind: ColumnKind| {
+        columns
+            .iter()
+            .find(|c| c.kind == kind && c.dataset.is_some() && c.dataset == column.dataset)
+    };
+
+    let mut long_records = vec![];
+    for rcrd in rows {
+        let date = NaiveDate::from_yo_opt(rcrd.year, rcrd.doy).unwrap();
+        for column in &columns {
+            if [ColumnKind::Site, ColumnKind::Goodpix, ColumnKind::Source].contains(&column.kind) {
+                continue;
+            }
+            let Some(value) = get_column_value(rcrd, column).and_then(|value| value.as_f64())
+            else {
+                continue;
+            };
+            let (goodpix, source) = match column.kind {
+                ColumnKind::Flux => (None, Some("tower".to_string())),
+                ColumnKind::Value => (
+                    find_dataset_column(column, ColumnKind::Goodpix)
+                        .and_then(|goodpix| get_column_value(rcrd, goodpix))
+                        .and_then(|goodpix| goodpix.as_f64())
+                        .map(|goodpix| goodpix as f32),
+                    match find_dataset_column(column, ColumnKind::Source) {
+                        Some(source) => rcrd.get_text(&source.name),
+                        None => column.product.clone(),
+                    },
+                ),
+                _ => (None, column.product.clone()),
+            };
+            long_records.push(LongRecord {
+                site: site_code,
+                date,
+                variable: column.name.clone(),
+                value,
+                goodpix,
+                source,
+                qc_filtered: column.qc_filtered,
+            });
+        }
+    }
+    Ok(long_records)
+}
@@ -2,7 +2,45 @@ pub mod run;
 pub use run::run;
 
 pub mod get_modis_data;
+#[allow(unused_imports)]
 pub use get_modis_data::*;
 
 pub mod define_metadata;
+#[allow(unused_imports)]
 pub use define_metadata::*;
+
+pub mod combine_sensors;
+
+pub mod land_cover;
+
+pub mod state_flags;
+
+pub mod disturbance;
+
+pub mod temporal_alignment;
+
+pub mod daily_output;
+
+pub mod smoothing;
+
+pub mod phenology;
+
+pub mod spectral_indices;
+
+pub mod parquet_output;
+
+pub mod netcdf_output;
+
+pub mod long_format;
+
+pub mod output_columns;
+
+pub mod provenance;
+
+pub mod inventory;
+
+pub mod checkpoint;
+
+pub mod update;
+
+pub mod extraction_cache;
@@ -0,0 +1,605 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::checkpoint::write_atomic;
+use crate::scripts::define_metadata::composite_days;
+use crate::scripts::output_columns::{get_column_value, get_output_columns};
+use byteorder::{BigEndian, WriteBytesExt};
+use chrono::prelude::*;
+use std::{error::Error, path::Path};
+
+// netcdf classic (CDF-1) tags and types
+const NC_DIMENSION: i32 = 0x0A;
+const NC_VARIABLE: i32 = 0x0B;
+const NC_ATTRIBUTE: i32 = 0x0C;
+const NC_CHAR: i32 = 2;
+const NC_INT: i32 = 4;
+const NC_FLOAT: i32 = 5;
+const NC_DOUBLE: i32 = 6;
+
+const FILL_VALUE: f64 = -9999.0;
+
+enum NcAttribute {
+    Text(String),
+    Int(i32),
+    Float(f32),
+    Double(f64),
+}
+
+enum NcValues {
+    Int(Vec<i32>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+struct NcVariable {
+    name: String,
+    // dimension ids, empty for a scalar
+    dims: Vec<i32>,
+    attributes: Vec<(String, NcAttribute)>,
+    values: NcValues,
+}
+
+fn text(value: &str) -> NcAttribute {
+    NcAttribute::Text(value.to_string())
+}
+
+// names and text are a length followed by the bytes, padded to 4 bytes
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    buf.write_i32::<BigEndian>(name.len() as i32).unwrap();
+    buf.extend_from_slice(name.as_bytes());
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn write_attributes(buf: &mut Vec<u8>, attributes: &[(String, NcAttribute)]) {
+    if attributes.is_empty() {
+        // ABSENT
+        buf.write_i64::<BigEndian>(0).unwrap();
+        return;
+    }
+    buf.write_i32::<BigEndian>(NC_ATTRIBUTE).unwrap();
+    buf.write_i32::<BigEndian>(attributes.len() as i32).unwrap();
+    for (name, attribute) in attributes {
+        write_name(buf, name);
+        match attribute {
+            NcAttribute::Text(value) => {
+                buf.write_i32::<BigEndian>(NC_CHAR).unwrap();
+                write_name(buf, value);
+            }
+            NcAttribute::Int(value) => {
+                buf.write_i32::<BigEndian>(NC_INT).unwrap();
+                buf.write_i32::<BigEndian>(1).unwrap();
+                buf.write_i32::<BigEndian>(*value).unwrap();
+            }
+            NcAttribute::Float(value) => {
+                buf.write_i32::<BigEndian>(NC_FLOAT).unwrap();
+                buf.write_i32::<BigEndian>(1).unwrap();
+                buf.write_f32::<BigEndian>(*value).unwrap();
+            }
+            NcAttribute::Double(value) => {
+                buf.write_i32::<BigEndian>(NC_DOUBLE).unwrap();
+                buf.write_i32::<BigEndian>(1).unwrap();
+                buf.write_f64::<BigEndian>(*value).unwrap();
+            }
+        }
+    }
+}
+
+fn variable_size(variable: &NcVariable) -> usize {
+    let size = match &variable.values {
+        NcValues::Int(values) => values.len() * 4,
+        NcValues::Float(values) => values.len() * 4,
+        NcValues::Double(values) => values.len() * 8,
+    };
+    size.next_multiple_of(4)
+}
+
+// header with the data offset of every variable. the header length doesn't depend on the offsets,
+// so it is written once with zeros to measure it and again with the real offsets
+fn write_header(
+    dimensions: &[(&str, usize)],
+    global_attributes: &[(String, NcAttribute)],
+    variables: &[NcVariable],
+    offsets: &[i32],
+) -> Vec<u8> {
+    let mut buf = b"CDF\x01".to_vec();
+    // no record dimension
+    buf.write_i32::<BigEndian>(0).unwrap();
+    buf.write_i32::<BigEndian>(NC_DIMENSION).unwrap();
+    buf.write_i32::<BigEndian>(dimensions.len() as i32).unwrap();
+    for (name, length) in dimensions {
+        write_name(&mut buf, name);
+        buf.write_i32::<BigEndian>(*length as i32).unwrap();
+    }
+    write_attributes(&mut buf, global_attributes);
+    buf.write_i32::<BigEndian>(NC_VARIABLE).unwrap();
+    buf.write_i32::<BigEndian>(variables.len() as i32).unwrap();
+    for (variable, offset) in variables.iter().zip(offsets) {
+        write_name(&mut buf, &variable.name);
+        buf.write_i32::<BigEndian>(variable.dims.len() as i32)
+            .unwrap();
+        for dim in &variable.dims {
+            buf.write_i32::<BigEndian>(*dim).unwrap();
+        }
+        write_attributes(&mut buf, &variable.attributes);
+        buf.write_i32::<BigEndian>(match variable.values {
+            NcValues::Int(_) => NC_INT,
+            NcValues::Float(_) => NC_FLOAT,
+            NcValues::Double(_) => NC_DOUBLE,
+        })
+        .unwrap();
+        buf.write_i32::<BigEndian>(variable_size(variable) as i32)
+            .unwrap();
+        buf.write_i32::<BigEndian>(*offset).unwrap();
+    }
+    buf
+}
+
+// catalog units written the way udunits reads them
+fn get_cf_units(units: &str) -> &str {
+    match units {
+        "m2/m2" => "m2 m-2",
+        "kgC/m2/8day" => "kg m-2 (8 day)-1",
+        "kgC/m2/year" => "kg m-2 year-1",
+        "mm/8day" => "mm (8 day)-1",
+        "mm/day" => "mm day-1",
+        "J/m2/day" => "J m-2 day-1",
+        "MJ/m2/day" => "MJ m-2 day-1",
+        "W/m2" => "W m-2",
+        "degrees" => "degree",
+        "day of year" | "class" => "1",
+        _ => units,
+    }
+}
+
+// CF standard names for the datasets that have one
+fn get_cf_standard_name(dataset: &str) -> Option<&'static str> {
+    match dataset {
+        "Lai" | "VNP_Lai" => Some("leaf_area_index"),
+        "Gpp" | "Gpp_MOD17A2H" => Some("gross_primary_productivity_of_biomass_expressed_as_carbon"),
+        "Npp" => Some("net_primary_productivity_of_biomass_expressed_as_carbon"),
+        "LE" => Some("surface_upward_latent_heat_flux"),
+        "LST_Day" | "LST_Night" | "VNP_LST_Day" | "VNP_LST_Night" => Some("surface_temperature"),
+        d if d.starts_with("Albedo_") => Some("surface_albedo"),
+        _ => None,
+    }
+}
+
+// CF units and standard name of the tower columns, the input csv has daily means of every 8 days
+fn get_flux_cf_attributes(name: &str) -> (&'static str, Option<&'static str>) {
+    match name {
+        "solar_radiation" => (
+            "MJ m-2 day-1",
+            Some("surface_downwelling_shortwave_flux_in_air"),
+        ),
+        "air_temperature" => ("degC", Some("air_temperature")),
+        "vpd" => ("hPa", Some("water_vapor_saturation_deficit_in_air")),
+        "sensible_heat" => ("MJ m-2 day-1", Some("surface_upward_sensible_heat_flux")),
+        "evapotranspiration" => ("mm day-1", Some("lwe_water_evapotranspiration_rate")),
+        "gpp" => (
+            "g m-2 day-1",
+            Some("gross_primary_productivity_of_biomass_expressed_as_carbon"),
+        ),
+        // respiration and nee have no standard name for the whole ecosystem
+        _ => ("g m-2 day-1", None),
+    }
+}
+
+// Attributes of the data variable of an output column, with a _FillValue of the variable's type.
+// None for the columns that aren't data variables: the site columns are the dimensions, coordinates and global attributes,
+// and netcdf classic has no strings for the _source columns (source_product has the product of every variable)
+fn get_column_attributes(
+    column: &ColumnDescriptor,
+    columns: &[ColumnDescriptor],
+) -> Option<Vec<(String, NcAttribute)>> {
+    let fill_value = match column.column_type {
+        ColumnType::Text => return None,
+        ColumnType::Float32 => NcAttribute::Float(FILL_VALUE as f32),
+        ColumnType::Float64 => NcAttribute::Double(FILL_VALUE),
+        _ => NcAttribute::Int(FILL_VALUE as i32),
+    };
+    let mut attributes = vec![];
+    if column.kind == ColumnKind::Flux {
+        attributes.push((
+            "long_name".to_string(),
+            text(&format!("flux tower {}", column.name.replace('_', " "))),
+        ));
+        let (units, standard_name) = get_flux_cf_attributes(&column.name);
+        if let Some(standard_name) = standard_name {
+            attributes.push(("standard_name".to_string(), text(standard_name)));
+        }
+        attributes.push(("units".to_string(), text(units)));
+        attributes.push(("_FillValue".to_string(), fill_value));
+        attributes.push(("coordinates".to_string(), text("lat lon")));
+        attributes.push(("source_product".to_string(), text("tower")));
+        return Some(attributes);
+    }
+    let (Some(dataset), Some(product)) = (&column.dataset, &column.product) else {
+        return None;
+    };
+    let long_name = match column.kind {
+        ColumnKind::Goodpix => format!("fraction of good quality {product} {dataset} pixels"),
+        ColumnKind::Value => format!("{product} {dataset} tower window mean"),
+        ColumnKind::Smoothed => format!("{product} {dataset} tower window mean, smoothed"),
+        ColumnKind::Filled => {
+            format!("{product} {dataset} tower window mean, gaps filled from the smoothed series")
+        }
+        _ => format!(
+            "{} of the {product} {dataset} tower window",
+            column.name.replace('_', " ")
+        ),
+    };
+    attributes.push(("long_name".to_string(), text(&long_name)));
+    if [ColumnKind::Value, ColumnKind::Smoothed, ColumnKind::Filled].contains(&column.kind) {
+        if let Some(standard_name) = get_cf_standard_name(dataset) {
+            attributes.push(("standard_name".to_string(), text(standard_name)));
+        }
+    }
+    if let Some(units) = &column.units {
+        attributes.push(("units".to_string(), text(get_cf_units(units))));
+    }
+    attributes.push(("_FillValue".to_string(), fill_value));
+    if column.kind == ColumnKind::Value {
+        if let Some(goodpix) = columns.iter().find(|goodpix| {
+            goodpix.kind == ColumnKind::Goodpix && goodpix.dataset == column.dataset
+        }) {
+            attributes.push(("ancillary_variables".to_string(), text(&goodpix.name)));
+        }
+    }
+    attributes.push(("coordinates".to_string(), text("lat lon")));
+    attributes.push(("source_product".to_string(), text(product)));
+    Some(attributes)
+}
+
+// Write {site}.nc: a time series with one data variable per output column, see get_column_attributes.
+// time is the middle of each 8 day row with time_bnds covering the row, lat and lon are scalar coordinates
+pub fn write_netcdf_output(
+    site_code: &str,
+    rows: &[NewRecord],
+    path: &Path,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let columns = get_output_columns(config)?;
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+
+    let dimensions = [("time", rows.len()), ("nv", 2)];
+    let global_attributes = vec![
+        ("Conventions".to_string(), text("CF-1.8")),
+        ("featureType".to_string(), text("timeSeries")),
+        (
+            "title".to_string(),
+            text(&format!(
+                "MODIS and VIIRS values around flux tower {site_code}"
+            )),
+        ),
+        ("site_code".to_string(), text(site_code)),
+        (
+            "syear".to_string(),
+            text(
+                &rows
+                    .first()
+                    .map_or(String::new(), |rcrd| rcrd.syear.to_string()),
+            ),
+        ),
+        (
+            "eyear".to_string(),
+            text(
+                &rows
+                    .first()
+                    .map_or(String::new(), |rcrd| rcrd.eyear.to_string()),
+            ),
+        ),
+    ];
+
+    let mut time = vec![];
+    let mut time_bnds = vec![];
+    for rcrd in rows {
+        let start =
+            (NaiveDate::from_yo_opt(rcrd.year, rcrd.doy).unwrap() - epoch).num_days() as f64;
+        let days = composite_days(rcrd.year, rcrd.doy) as f64;
+        time.push(start + days / 2.0);
+        time_bnds.push(start);
+        time_bnds.push(start + days);
+    }
+    let lat = rows.first().map_or(FILL_VALUE, |rcrd| rcrd.lat);
+    let lon = rows.first().map_or(FILL_VALUE, |rcrd| rcrd.lon);
+
+    let mut variables = vec![
+        NcVariable {
+            name: "time".to_string(),
+            dims: vec![0],
+            attributes: vec![
+                ("standard_name".to_string(), text("time")),
+                ("long_name".to_string(), text("middle of the composite row")),
+                ("units".to_string(), text("days since 1970-01-01 00:00:00")),
+                ("calendar".to_string(), text("standard")),
+                ("bounds".to_string(), text("time_bnds")),
+            ],
+            values: NcValues::Double(time),
+        },
+        NcVariable {
+            name: "time_bnds".to_string(),
+            dims: vec![0, 1],
+            attributes: vec![],
+            values: NcValues::Double(time_bnds),
+        },
+        NcVariable {
+            name: "lat".to_string(),
+            dims: vec![],
+            attributes: vec![
+                ("standard_name".to_string(), text("latitude")),
+                ("long_name".to_string(), text("tower latitude")),
+                ("units".to_string(), text("degrees_north")),
+            ],
+            values: NcValues::Double(vec![lat]),
+        },
+        NcVariable {
+            name: "lon".to_string(),
+            dims: vec![],
+            attributes: vec![
+                ("standard_name".to_string(), text("longitude")),
+                ("long_name".to_string(), text("tower longitude")),
+                ("units".to_string(), text("degrees_east")),
+            ],
+            values: NcValues::Double(vec![lon]),
+        },
+    ];
+
+    for column in &columns {
+        let Some(attributes) = get_column_attributes(column, &columns) else {
+            continue;
+        };
+        let values: Vec<Option<f64>> = rows
+            .iter()
+            .map(|rcrd| get_column_value(rcrd, column).and_then(|value| value.as_f64()))
+            .collect();
+        let values = match column.column_type {
+            ColumnType::Float32 => NcValues::Float(
+                values
+                    .iter()
+                    .map(|v| v.map_or(FILL_VALUE as f32, |v| v as f32))
+                    .collect(),
+            ),
+            ColumnType::Float64 => {
+                NcValues::Double(values.iter().map(|v| v.unwrap_or(FILL_VALUE)).collect())
+            }
+            _ => NcValues::Int(
+                values
+                    .iter()
+                    .map(|v| v.map_or(FILL_VALUE as i32, |v| v as i32))
+                    .collect(),
+            ),
+        };
+        variables.push(NcVariable {
+            name: column.name.clone(),
+            dims: vec![0],
+            attributes,
+            values,
+        });
+    }
+
+    // data of each variable follows the header in order
+    let header_len = write_header(
+        &dimensions,
+        &global_attributes,
+        &variables,
+        &vec![0; variables.len()],
+    )
+    .len();
+    let mut offsets = vec![];
+    let mut offset = header_len;
+    for variable in &variables {
+        offsets.push(offset as i32);
+        offset += variable_size(variable);
+    }
+    let mut buf = write_header(&dimensions, &global_attributes, &variables, &offsets);
+    for variable in &variables {
+        match &variable.values {
+            NcValues::Int(values) => {
+                for value in values {
+                    buf.write_i32::<BigEndian>(*value)?;
+                }
+            }
+            NcValues::Float(values) => {
+                for value in values {
+                    buf.write_f32::<BigEndian>(*value)?;
+                }
+            }
+            NcValues::Double(values) => {
+                for value in values {
+                    buf.write_f64::<BigEndian>(*value)?;
+                }
+            }
+        }
+        buf.resize(buf.len().next_multiple_of(4), 0);
+    }
+    write_atomic(path, &buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::{collections::HashMap, fs, io::Cursor};
+
+    #[derive(Debug, PartialEq)]
+    enum Attribute {
+        Text(String),
+        Numbers(Vec<f64>),
+    }
+
+    struct Variable {
+        dims: Vec<usize>,
+        attributes: HashMap<String, Attribute>,
+        values: Vec<f64>,
+    }
+
+    // reads a name or text attribute, a length then the bytes padded to 4
+    fn read_name(rdr: &mut Cursor<&[u8]>) -> String {
+        let len = rdr.read_i32::<BigEndian>().unwrap() as usize;
+        let start = rdr.position() as usize;
+        let name = String::from_utf8(rdr.get_ref()[start..start + len].to_vec()).unwrap();
+        rdr.set_position(start.saturating_add(len).next_multiple_of(4) as u64);
+        name
+    }
+
+    fn read_values(rdr: &mut Cursor<&[u8]>, nc_type: i32, count: usize) -> Vec<f64> {
+        (0..count)
+            .map(|_| match nc_type {
+                NC_INT => rdr.read_i32::<BigEndian>().unwrap() as f64,
+                NC_FLOAT => rdr.read_f32::<BigEndian>().unwrap() as f64,
+                NC_DOUBLE => rdr.read_f64::<BigEndian>().unwrap(),
+                _ => panic!("unexpected type {nc_type}"),
+            })
+            .collect()
+    }
+
+    fn read_attributes(rdr: &mut Cursor<&[u8]>) -> HashMap<String, Attribute> {
+        let mut attributes = HashMap::new();
+        let tag = rdr.read_i32::<BigEndian>().unwrap();
+        let count = rdr.read_i32::<BigEndian>().unwrap();
+        if tag == 0 {
+            return attributes;
+        }
+        assert_eq!(tag, NC_ATTRIBUTE);
+        for _ in 0..count {
+            let name = read_name(rdr);
+            let nc_type = rdr.read_i32::<BigEndian>().unwrap();
+            let attribute = if nc_type == NC_CHAR {
+                Attribute::Text(read_name(rdr))
+            } else {
+                let count = rdr.read_i32::<BigEndian>().unwrap() as usize;
+                Attribute::Numbers(read_values(rdr, nc_type, count))
+            };
+            attributes.insert(name, attribute);
+        }
+        attributes
+    }
+
+    // (dimensions, global attributes, variables by name)
+    type NetcdfFile = (
+        Vec<(String, usize)>,
+        HashMap<String, Attribute>,
+        HashMap<String, Variable>,
+    );
+
+    // a reader for the parts of netcdf classic the writer uses
+    fn read_netcdf(buf: &[u8]) -> NetcdfFile {
+        assert_eq!(&buf[..4], b"CDF\x01");
+        let mut rdr = Cursor::new(buf);
+        rdr.set_position(8);
+        assert_eq!(rdr.read_i32::<BigEndian>().unwrap(), NC_DIMENSION);
+        let dimensions: Vec<(String, usize)> = (0..rdr.read_i32::<BigEndian>().unwrap())
+            .map(|_| {
+                let name = read_name(&mut rdr);
+                (name, rdr.read_i32::<BigEndian>().unwrap() as usize)
+            })
+            .collect();
+        let global_attributes = read_attributes(&mut rdr);
+        assert_eq!(rdr.read_i32::<BigEndian>().unwrap(), NC_VARIABLE);
+        let mut variables = HashMap::new();
+        for _ in 0..rdr.read_i32::<BigEndian>().unwrap() {
+            let name = read_name(&mut rdr);
+            let dims: Vec<usize> = (0..rdr.read_i32::<BigEndian>().unwrap())
+                .map(|_| dimensions[rdr.read_i32::<BigEndian>().unwrap() as usize].1)
+                .collect();
+            let attributes = read_attributes(&mut rdr);
+            let nc_type = rdr.read_i32::<BigEndian>().unwrap();
+            let _size = rdr.read_i32::<BigEndian>().unwrap();
+            let offset = rdr.read_i32::<BigEndian>().unwrap() as u64;
+            let header_position = rdr.position();
+            rdr.set_position(offset);
+            let values = read_values(&mut rdr, nc_type, dims.iter().product());
+            rdr.set_position(header_position);
+            variables.insert(
+                name,
+                Variable {
+                    dims,
+                    attributes,
+                    values,
+                },
+            );
+        }
+        (dimensions, global_attributes, variables)
+    }
+
+    #[test]
+    fn writes_dimensions_attributes_and_values() {
+        let config: Config = toml::from_str("").unwrap();
+        let site = NewRecord {
+            site_code: "JP-Tkc",
+            lat: 36.14,
+            lon: 137.37,
+            syear: 2001,
+            eyear: 2010,
+            year: 2001,
+            ..Default::default()
+        };
+        let mut first = NewRecord {
+            doy: 1,
+            gpp: Some(3.25),
+            ..site.clone()
+        };
+        first.set_value("lai", Some(ColumnValue::Float64(1.5)));
+        first.set_value("lai_goodpix", Some(ColumnValue::Float32(0.75)));
+        first.set_value("lai_source", Some(ColumnValue::Text("MOD".to_string())));
+        first.set_value("lc_igbp_majority", Some(ColumnValue::UInt8(4)));
+        first.set_value("burned", Some(ColumnValue::Boolean(true)));
+        let second = NewRecord { doy: 9, ..site };
+
+        let path = std::env::temp_dir().join(format!("netcdf_output_{}.nc", std::process::id()));
+        write_netcdf_output("JP-Tkc", &[first, second], &path, &config).unwrap();
+        let buf = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (dimensions, global_attributes, variables) = read_netcdf(&buf);
+
+        assert_eq!(
+            dimensions,
+            vec![("time".to_string(), 2), ("nv".to_string(), 2)]
+        );
+        assert_eq!(
+            global_attributes["site_code"],
+            Attribute::Text("JP-Tkc".to_string())
+        );
+        // 2001-01-01 is day 11323, the rows cover 8 days each
+        assert_eq!(variables["time"].values, vec![11327.0, 11335.0]);
+        assert_eq!(
+            variables["time_bnds"].values,
+            vec![11323.0, 11331.0, 11331.0, 11339.0]
+        );
+        assert!(variables["lat"].dims.is_empty());
+        assert_eq!(variables["lat"].values, vec![36.14]);
+
+        let lai = &variables["lai"];
+        assert_eq!(lai.values, vec![1.5, FILL_VALUE]);
+        assert_eq!(
+            lai.attributes["units"],
+            Attribute::Text("m2 m-2".to_string())
+        );
+        assert_eq!(
+            lai.attributes["ancillary_variables"],
+            Attribute::Text("lai_goodpix".to_string())
+        );
+        assert_eq!(variables["lai_goodpix"].values, vec![0.75, FILL_VALUE]);
+        assert_eq!(variables["gpp"].values, vec![3.25, FILL_VALUE]);
+        assert_eq!(
+            variables["gpp"].attributes["standard_name"],
+            Attribute::Text(
+                "gross_primary_productivity_of_biomass_expressed_as_carbon".to_string()
+            )
+        );
+        assert_eq!(
+            variables["gpp"].attributes["units"],
+            Attribute::Text("g m-2 day-1".to_string())
+        );
+        assert_eq!(variables["lc_igbp_majority"].values, vec![4.0, FILL_VALUE]);
+        assert_eq!(
+            variables["burned"].attributes["_FillValue"],
+            Attribute::Numbers(vec![FILL_VALUE])
+        );
+        assert_eq!(variables["burned"].values, vec![1.0, FILL_VALUE]);
+        assert!(variables.contains_key("lai_smoothed"));
+        assert!(!variables.contains_key("lai_source"));
+        assert!(!variables.contains_key("site_code"));
+    }
+}
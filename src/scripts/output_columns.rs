@@ -0,0 +1,320 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::disturbance::get_disturbance_columns;
+use crate::scripts::land_cover::get_land_cover_columns;
+use crate::scripts::state_flags::get_state_flag_columns;
+use std::error::Error;
+
+// the NewRecord fields that say which site and row it is
+fn get_site_columns() -> Vec<ColumnDescriptor> {
+    [
+        ("site_code", ColumnType::Text),
+        ("lat", ColumnType::Float64),
+        ("lon", ColumnType::Float64),
+        ("syear", ColumnType::Int32),
+        ("eyear", ColumnType::Int32),
+        ("year", ColumnType::Int32),
+        ("doy", ColumnType::Int32),
+    ]
+    .into_iter()
+    .map(|(name, column_type)| ColumnDescriptor {
+        name: name.to_string(),
+        column_type,
+        kind: ColumnKind::Site,
+        dataset: None,
+        product: None,
+        units: None,
+        scale_factor: None,
+        qc_filtered: false,
+    })
+    .collect()
+}
+
+// the NewRecord fields read from the flux data file
+fn get_flux_columns() -> Vec<ColumnDescriptor> {
+    [
+        "solar_radiation",
+        "air_temperature",
+        "vpd",
+        "sensible_heat",
+        "evapotranspiration",
+        "respiration",
+        "nee",
+        "gpp",
+    ]
+    .into_iter()
+    .map(|name| ColumnDescriptor {
+        name: name.to_string(),
+        column_type: ColumnType::Float64,
+        kind: ColumnKind::Flux,
+        dataset: None,
+        product: None,
+        units: None,
+        scale_factor: None,
+        qc_filtered: false,
+    })
+    .collect()
+}
+
+// A column worked out from the window of a dataset rather than its mean, e.g. the land cover majority class
+pub fn get_statistic_column(
+    name: &str,
+    column_type: ColumnType,
+    dm: &DatasetMetadata,
+    units: &str,
+) -> ColumnDescriptor {
+    ColumnDescriptor {
+        name: name.to_string(),
+        column_type,
+        kind: ColumnKind::Statistic,
+        dataset: Some(dm.dataset.clone()),
+        product: Some(dm.product.clone()),
+        units: Some(units.to_string()),
+        scale_factor: None,
+        qc_filtered: false,
+    }
+}
+
+// Value and goodpix columns of a dataset, then its _source column when it can be read from aqua too
+// and its _smoothed and _filled columns when it is smoothed
+fn get_dataset_columns(
+    dataset_name: &str,
+    has_source: bool,
+    config: &Config,
+) -> Vec<ColumnDescriptor> {
+    let column = get_dataset_column(dataset_name, config);
+    let mut dm = get_catalog_metadata(dataset_name, config);
+    let scale_factor = dm.scale_factor;
+    // the units the values are written in, only the MOD16A2GF ones change and not with the date
+    apply_unit_conversion(&mut dm, 2001, 1, config);
+    // the surface reflectance bands only average the pixels their state layer has as clear, see combine_sensors
+    let qc_filtered = config.sur_refl_state_mask && dataset_name.starts_with("sur_refl_b");
+    let value_column = |name: String, column_type: ColumnType, kind: ColumnKind| ColumnDescriptor {
+        name,
+        column_type,
+        kind,
+        dataset: Some(dataset_name.to_string()),
+        product: Some(dm.product.clone()),
+        units: Some(dm.units.clone()),
+        scale_factor: Some(scale_factor),
+        qc_filtered,
+    };
+    let mut columns = vec![
+        value_column(column.clone(), ColumnType::Float64, ColumnKind::Value),
+        ColumnDescriptor {
+            units: Some("1".to_string()),
+            scale_factor: None,
+            ..value_column(
+                format!("{column}_goodpix"),
+                ColumnType::Float32,
+                ColumnKind::Goodpix,
+            )
+        },
+    ];
+    if has_source && get_sensor_metadata(dataset_name, Sensor::Aqua, config).is_some() {
+        columns.push(ColumnDescriptor {
+            units: None,
+            scale_factor: None,
+            ..value_column(
+                format!("{column}_source"),
+                ColumnType::Text,
+                ColumnKind::Source,
+            )
+        });
+    }
+    if get_smoothed_dataset_names().contains(&dataset_name.to_string()) {
+        columns.push(value_column(
+            format!("{column}_smoothed"),
+            ColumnType::Float64,
+            ColumnKind::Smoothed,
+        ));
+        columns.push(value_column(
+            format!("{column}_filled"),
+            ColumnType::Float64,
+            ColumnKind::Filled,
+        ));
+    }
+    columns
+}
+
+// the indices computed pixel by pixel from the nadir reflectance bands, only the pixels with the best qc go in them
+fn get_derived_index_columns(config: &Config) -> Vec<ColumnDescriptor> {
+    get_derived_index_expressions()
+        .into_iter()
+        .map(|(name, _)| name)
+        .chain(["band_math".to_string()])
+        .flat_map(|name| {
+            let column = get_dataset_column(&name, config);
+            let index_column =
+                |column_name: String, column_type: ColumnType, kind: ColumnKind| ColumnDescriptor {
+                    name: column_name,
+                    column_type,
+                    kind,
+                    dataset: Some(name.clone()),
+                    product: Some("MCD43A4".to_string()),
+                    units: Some("1".to_string()),
+                    scale_factor: None,
+                    qc_filtered: true,
+                };
+            [
+                index_column(column.clone(), ColumnType::Float64, ColumnKind::Value),
+                index_column(
+                    format!("{column}_goodpix"),
+                    ColumnType::Float32,
+                    ColumnKind::Goodpix,
+                ),
+            ]
+        })
+        .collect()
+}
+
+// Every column of the wide csv, parquet and netcdf outputs, in the order they are written.
+// the MOD17 and MOD16 fluxes go next to the tower fluxes, then the land cover and disturbance of the window and the
+// other datasets, with the layers worked out from a group of datasets after it. datasets only in the config catalog go last
+pub fn get_column_descriptors(config: &Config) -> Vec<ColumnDescriptor> {
+    let mut columns = get_site_columns();
+    columns.extend(get_flux_columns());
+    let (flux_datasets, other_datasets): (Vec<String>, Vec<String>) =
+        get_dataset_names().into_iter().partition(|dataset_name| {
+            ["MOD17A2HGF", "MOD17A2H", "MOD17A3HGF", "MOD16A2GF"]
+                .contains(&get_dataset_metadata(dataset_name).product.as_str())
+        });
+    for dataset_name in &flux_datasets {
+        columns.extend(get_dataset_columns(dataset_name, true, config));
+    }
+    for dataset_name in get_land_cover_names() {
+        columns.extend(get_land_cover_columns(&dataset_name, config));
+    }
+    columns.extend(get_disturbance_columns(config));
+    for dataset_name in &other_datasets {
+        columns.extend(get_dataset_columns(dataset_name, true, config));
+        match dataset_name.as_str() {
+            "LST_Night" => {
+                for layer_name in get_acquisition_layer_names() {
+                    columns.extend(get_dataset_columns(&layer_name, false, config));
+                }
+            }
+            "Nadir_Reflectance_Band7" => columns.extend(get_derived_index_columns(config)),
+            "sur_refl_b07" => {
+                for dataset_name in get_state_flag_names() {
+                    columns.extend(get_state_flag_columns(&dataset_name, config));
+                }
+            }
+            _ => {}
+        }
+    }
+    for dataset_name in get_catalog_dataset_names(config) {
+        columns.extend(get_dataset_columns(&dataset_name, true, config));
+    }
+    columns
+}
+
+// The columns written to the wide outputs, output_columns in the config picks and orders them
+pub fn get_output_columns(config: &Config) -> Result<Vec<ColumnDescriptor>, Box<dyn Error>> {
+    let columns = get_column_descriptors(config);
+    let Some(output_columns) = &config.output_columns else {
+        return Ok(columns);
+    };
+    let mut selected_columns = vec![];
+    for output_column in output_columns {
+        match columns.iter().find(|column| &column.name == output_column) {
+            Some(column) => selected_columns.push(column.clone()),
+            None => {
+                return Err(From::from(format!(
+                    "output column {output_column} doesn't exist"
+                )))
+            }
+        }
+    }
+    Ok(selected_columns)
+}
+
+// value of a column in a record, None when it is missing
+pub fn get_column_value(rcrd: &NewRecord, column: &ColumnDescriptor) -> Option<ColumnValue> {
+    match column.name.as_str() {
+        "site_code" => Some(ColumnValue::Text(rcrd.site_code.to_string())),
+        "lat" => Some(ColumnValue::Float64(rcrd.lat)),
+        "lon" => Some(ColumnValue::Float64(rcrd.lon)),
+        "syear" => Some(ColumnValue::Int32(rcrd.syear)),
+        "eyear" => Some(ColumnValue::Int32(rcrd.eyear)),
+        "year" => Some(ColumnValue::Int32(rcrd.year)),
+        "doy" => Some(ColumnValue::Int32(rcrd.doy as i32)),
+        "solar_radiation" => rcrd.solar_radiation.map(ColumnValue::Float64),
+        "air_temperature" => rcrd.air_temperature.map(ColumnValue::Float64),
+        "vpd" => rcrd.vpd.map(ColumnValue::Float64),
+        "sensible_heat" => rcrd.sensible_heat.map(ColumnValue::Float64),
+        "evapotranspiration" => rcrd.evapotranspiration.map(ColumnValue::Float64),
+        "respiration" => rcrd.respiration.map(ColumnValue::Float64),
+        "nee" => rcrd.nee.map(ColumnValue::Float64),
+        "gpp" => rcrd.gpp.map(ColumnValue::Float64),
+        name => rcrd.values.get(name).cloned(),
+    }
+}
+
+// Reads a column value back from the text the csv output has for it, empty text is a missing value
+pub fn parse_column_value(
+    text: &str,
+    column: &ColumnDescriptor,
+) -> Result<Option<ColumnValue>, Box<dyn Error>> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let value = match column.column_type {
+        ColumnType::Text => Ok(ColumnValue::Text(text.to_string())),
+        ColumnType::Int32 => text
+            .parse()
+            .map(ColumnValue::Int32)
+            .map_err(|e| e.to_string()),
+        ColumnType::Int64 => text
+            .parse()
+            .map(ColumnValue::Int64)
+            .map_err(|e| e.to_string()),
+        ColumnType::UInt8 => text
+            .parse()
+            .map(ColumnValue::UInt8)
+            .map_err(|e| e.to_string()),
+        ColumnType::Float32 => text
+            .parse()
+            .map(ColumnValue::Float32)
+            .map_err(|e| e.to_string()),
+        ColumnType::Float64 => text
+            .parse()
+            .map(ColumnValue::Float64)
+            .map_err(|e| e.to_string()),
+        ColumnType::Boolean => text
+            .parse()
+            .map(ColumnValue::Boolean)
+            .map_err(|e| e.to_string()),
+    };
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => Err(From::from(format!(
+            "{} value \"{text}\" isn't a {:?}: {e}",
+            column.name, column.column_type
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qc_filtered_follows_the_pixel_filters() {
+        let qc_filtered = |config: &Config, name: &str| {
+            get_column_descriptors(config)
+                .into_iter()
+                .find(|column| column.name == name)
+                .unwrap()
+                .qc_filtered
+        };
+        let config: Config = toml::from_str("").unwrap();
+        assert!(qc_filtered(&config, "sur_refl_b01"));
+        assert!(qc_filtered(&config, "sur_refl_b07_goodpix"));
+        assert!(!qc_filtered(&config, "nadir_ref_band1"));
+        assert!(qc_filtered(&config, "ndwi"));
+        let config: Config = toml::from_str("sur_refl_state_mask = false").unwrap();
+        assert!(!qc_filtered(&config, "sur_refl_b01"));
+    }
+}
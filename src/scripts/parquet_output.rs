@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::output_columns::{get_column_value, get_output_columns};
+use arrow_array::{
+    types::Int32Type, ArrayRef, BooleanArray, Date32Array, DictionaryArray, Float32Array,
+    Float64Array, Int32Array, Int64Array, RecordBatch, StringArray, UInt8Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use chrono::NaiveDate;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::{collections::HashMap, error::Error, fs::File, path::Path, sync::Arc};
+
+// Arrow type of an output column. the site columns repeat on every row, so their text is dictionary encoded
+fn get_arrow_type(column: &ColumnDescriptor) -> DataType {
+    match column.column_type {
+        ColumnType::Text if column.kind == ColumnKind::Site => {
+            DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+        }
+        ColumnType::Text => DataType::Utf8,
+        ColumnType::Int32 => DataType::Int32,
+        ColumnType::Int64 => DataType::Int64,
+        ColumnType::UInt8 => DataType::UInt8,
+        ColumnType::Float32 => DataType::Float32,
+        ColumnType::Float64 => DataType::Float64,
+        ColumnType::Boolean => DataType::Boolean,
+    }
+}
+
+// dataset, product, scale factor and units of a column as field metadata.
+// scale_factor is the product's own, the values are already scaled (and converted to the units given)
+fn get_field_metadata(column: &ColumnDescriptor) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    if let Some(dataset) = &column.dataset {
+        metadata.insert("dataset".to_string(), dataset.clone());
+    }
+    if let Some(product) = &column.product {
+        metadata.insert("product".to_string(), product.clone());
+    }
+    if let Some(scale_factor) = column.scale_factor {
+        metadata.insert("scale_factor".to_string(), scale_factor.to_string());
+    }
+    if let Some(units) = &column.units {
+        metadata.insert("units".to_string(), units.clone());
+    }
+    metadata
+}
+
+// Schema of the wide layout, the output columns with their dataset, product, scale factor and units as field metadata
+pub fn get_parquet_schema(config: &Config) -> Result<SchemaRef, Box<dyn Error>> {
+    let fields: Vec<Field> = get_output_columns(config)?
+        .iter()
+        .map(|column| {
+            Field::new(&column.name, get_arrow_type(column), true)
+                .with_metadata(get_field_metadata(column))
+        })
+        .collect();
+    Ok(Arc::new(Schema::new(fields)))
+}
+
+// values of a column for every row, with an error for a value that isn't the column's type
+fn get_typed_values<T>(
+    rows: &[NewRecord],
+    column: &ColumnDescriptor,
+    get_value: impl Fn(ColumnValue) -> Option<T>,
+) -> Result<Vec<Option<T>>, Box<dyn Error>> {
+    let mut values = vec![];
+    for rcrd in rows {
+        let value = match get_column_value(rcrd, column) {
+            None => None,
+            Some(value) => match get_value(value.clone()) {
+                Some(value) => Some(value),
+                None => {
+                    return Err(From::from(format!(
+                        "{} {}.{}: {} value {value:?} isn't a {:?}",
+                        rcrd.site_code, rcrd.year, rcrd.doy, column.name, column.column_type
+                    )))
+                }
+            },
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// One record batch with all of a site's rows, missing values are null
+pub fn get_record_batch(
+    rows: &[NewRecord],
+    schema: &SchemaRef,
+    config: &Config,
+) -> Result<RecordBatch, Box<dyn Error>> {
+    let columns = get_output_columns(config)?;
+    if schema.fields().len() != columns.len() {
+        return Err(From::from(format!(
+            "parquet schema has {} columns, the output has {}",
+            schema.fields().len(),
+            columns.len()
+        )));
+    }
+    let mut arrays: Vec<ArrayRef> = vec![];
+    for (field, column) in schema.fields().iter().zip(columns) {
+        if field.name() != &column.name || field.data_type() != &get_arrow_type(&column) {
+            return Err(From::from(format!(
+                "parquet schema has {} {} where the output has {} {}",
+                field.name(),
+                field.data_type(),
+                column.name,
+                get_arrow_type(&column)
+            )));
+        }
+        let text = |value| match value {
+            ColumnValue::Text(x) => Some(x),
+            _ => None,
+        };
+        let array: ArrayRef = match column.column_type {
+            ColumnType::Text if column.kind == ColumnKind::Site => Arc::new(
+                get_typed_values(rows, &column, text)?
+                    .iter()
+                    .map(|value| value.as_deref())
+                    .collect::<DictionaryArray<Int32Type>>(),
+            ),
+            ColumnType::Text => Arc::new(StringArray::from(get_typed_values(rows, &column, text)?)),
+            ColumnType::Int32 => Arc::new(Int32Array::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::Int32(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+            ColumnType::Int64 => Arc::new(Int64Array::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::Int64(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+            ColumnType::UInt8 => Arc::new(UInt8Array::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::UInt8(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+            ColumnType::Boolean => Arc::new(BooleanArray::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::Boolean(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+            ColumnType::Float32 => Arc::new(Float32Array::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::Float32(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+            ColumnType::Float64 => Arc::new(Float64Array::from(get_typed_values(
+                rows,
+                &column,
+                |value| match value {
+                    ColumnValue::Float64(x) => Some(x),
+                    _ => None,
+                },
+            )?)),
+        };
+        arrays.push(array);
+    }
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+// Schema of the long layout, see LongRecord
+pub fn get_long_parquet_schema() -> SchemaRef {
+    let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+    Arc::new(Schema::new(vec![
+        Field::new("site", dictionary.clone(), false),
+        Field::new("date", DataType::Date32, false),
+        Field::new("variable", dictionary, false),
+        Field::new("value", DataType::Float64, false),
+        Field::new("goodpix", DataType::Float32, true),
+        Field::new("source", DataType::Utf8, true),
+        Field::new("qc_filtered", DataType::Boolean, false),
+    ]))
+}
+
+pub fn get_long_record_batch(
+    long_records: &[LongRecord],
+    schema: &SchemaRef,
+) -> Result<RecordBatch, Box<dyn Error>> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let arrays: Vec<ArrayRef> = vec![
+        Arc::new(
+            long_records
+                .iter()
+                .map(|r| r.site)
+                .collect::<DictionaryArray<Int32Type>>(),
+        ),
+        Arc::new(Date32Array::from_iter_values(
+            long_records
+                .iter()
+                .map(|r| (r.date - epoch).num_days() as i32),
+        )),
+        Arc::new(
+            long_records
+                .iter()
+                .map(|r| r.variable.as_str())
+                .collect::<DictionaryArray<Int32Type>>(),
+        ),
+        Arc::new(Float64Array::from_iter_values(
+            long_records.iter().map(|r| r.value),
+        )),
+        Arc::new(Float32Array::from_iter(
+            long_records.iter().map(|r| r.goodpix),
+        )),
+        Arc::new(StringArray::from_iter(
+            long_records.iter().map(|r| r.source.as_deref()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            long_records.iter().map(|r| Some(r.qc_filtered)),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+pub fn open_parquet_writer(
+    path: &Path,
+    schema: &SchemaRef,
+) -> Result<ArrowWriter<File>, Box<dyn Error>> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    Ok(ArrowWriter::try_new(
+        File::create(path)?,
+        schema.clone(),
+        Some(props),
+    )?)
+}
@@ -0,0 +1,211 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::checkpoint::{finish_temp_file, get_temp_path};
+use crate::scripts::define_metadata::composite_days;
+use crate::scripts::get_modis_data::get_modis_values;
+use crate::scripts::smoothing::{double_logistic_value, fit_double_logistic};
+use chrono::prelude::*;
+use csv::WriterBuilder;
+use std::{error::Error, path::PathBuf};
+
+// transition dates from a fitted double logistic curve evaluated on every day of the year.
+// green-up and maturity are where the rising side passes the onset and maturity fractions of its amplitude,
+// senescence and dormancy where the falling side drops back below them
+fn get_phenology_metrics<'a>(
+    site_code: &'a str,
+    year: i32,
+    series: &'a str,
+    points: &[(f64, f64, f64)],
+    config: &Config,
+) -> PhenologyRecord<'a> {
+    let mut rcrd = PhenologyRecord {
+        site_code,
+        year,
+        series,
+        green_up: None,
+        maturity: None,
+        senescence: None,
+        dormancy: None,
+        season_length: None,
+        peak_doy: None,
+        peak_value: None,
+        amplitude: None,
+        integrated: None,
+        cup_start: None,
+        cup_end: None,
+        cup_length: None,
+    };
+    let Some(params) = fit_double_logistic(points) else {
+        return rcrd;
+    };
+
+    let days_in_year = NaiveDate::from_ymd_opt(year, 12, 31).unwrap().ordinal();
+    let fitted: Vec<f64> = (1..=days_in_year)
+        .map(|doy| double_logistic_value(&params, doy as f64))
+        .collect();
+    let (peak_index, peak_value) = fitted
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+    let rising_base = fitted[..=peak_index]
+        .iter()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let falling_base = fitted[peak_index..]
+        .iter()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let rising_threshold = |fraction: f64| rising_base + fraction * (peak_value - rising_base);
+    let falling_threshold = |fraction: f64| falling_base + fraction * (peak_value - falling_base);
+
+    // doy is index + 1
+    let first_above = |threshold: f64| {
+        fitted[..=peak_index]
+            .iter()
+            .position(|value| *value >= threshold)
+            .map(|i| i as u32 + 1)
+    };
+    let last_above = |threshold: f64| {
+        fitted[peak_index..]
+            .iter()
+            .rposition(|value| *value >= threshold)
+            .map(|i| (i + peak_index) as u32 + 1)
+    };
+
+    // a curve that is already at its peak on the first or last day of the year has no transition on that side
+    if peak_index > 0 && rising_base < peak_value {
+        rcrd.green_up = first_above(rising_threshold(config.phenology_onset_fraction));
+        rcrd.maturity = first_above(rising_threshold(config.phenology_maturity_fraction));
+    }
+    if (peak_index as u32) < days_in_year - 1 && falling_base < peak_value {
+        rcrd.senescence = last_above(falling_threshold(config.phenology_maturity_fraction));
+        rcrd.dormancy = last_above(falling_threshold(config.phenology_onset_fraction));
+    }
+    if let (Some(green_up), Some(dormancy)) = (rcrd.green_up, rcrd.dormancy) {
+        rcrd.season_length = Some(dormancy - green_up + 1);
+        // sum of the fitted daily values over the growing season
+        let integrated: f64 = fitted[green_up as usize - 1..dormancy as usize]
+            .iter()
+            .sum();
+        rcrd.integrated = Some((integrated * 10000.0).round() / 10000.0);
+    }
+    rcrd.peak_doy = Some(peak_index as u32 + 1);
+    rcrd.peak_value = Some((peak_value * 10000.0).round() / 10000.0);
+    rcrd.amplitude =
+        Some((((peak_value - rising_base.min(falling_base)) * 10000.0).round()) / 10000.0);
+    rcrd
+}
+
+// Write {site}_phenology.csv with the NDVI and EVI transition dates of every year of the site,
+// and the same metrics from the tower gpp along with the carbon uptake period (nee below 0) to compare against
+pub fn write_phenology_output(
+    site_code: &str,
+    site_rows: &[NewRecord],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if !config.write_phenology || site_rows.is_empty() {
+        return Ok(());
+    }
+    let path = PathBuf::from(format!("{}/{}_phenology.csv", config.output_dir, site_code));
+    let mut wtr = WriterBuilder::new().from_path(get_temp_path(&path))?;
+
+    let mut start = 0;
+    while start < site_rows.len() {
+        let year = site_rows[start].year;
+        let end = (start..site_rows.len())
+            .find(|&i| site_rows[i].year != year)
+            .unwrap_or(site_rows.len());
+        let year_rows = &site_rows[start..end];
+
+        // vegetation indices are weighted by goodpix
+        for dataset_name in ["NDVI", "EVI"] {
+            let points: Vec<(f64, f64, f64)> = year_rows
+                .iter()
+                .filter_map(|rcrd| match get_modis_values(rcrd, dataset_name, config) {
+                    (Some(value), Some(goodpix)) => Some((rcrd.doy as f64, value, goodpix as f64)),
+                    _ => None,
+                })
+                .collect();
+            wtr.serialize(get_phenology_metrics(
+                site_code,
+                year,
+                dataset_name,
+                &points,
+                config,
+            ))?;
+        }
+
+        // tower gpp, every value has the same weight
+        let points: Vec<(f64, f64, f64)> = year_rows
+            .iter()
+            .filter_map(|rcrd| rcrd.gpp.map(|gpp| (rcrd.doy as f64, gpp, 1.0)))
+            .collect();
+        let mut tower_rcrd = get_phenology_metrics(site_code, year, "tower_gpp", &points, config);
+        // carbon uptake period from the first to the last row with net uptake
+        let uptake_rows: Vec<&NewRecord> = year_rows
+            .iter()
+            .filter(|rcrd| rcrd.nee.is_some_and(|nee| nee < 0.0))
+            .collect();
+        if let (Some(first), Some(last)) = (uptake_rows.first(), uptake_rows.last()) {
+            let cup_end = last.doy + composite_days(year, last.doy) - 1;
+            tower_rcrd.cup_start = Some(first.doy);
+            tower_rcrd.cup_end = Some(cup_end);
+            tower_rcrd.cup_length = Some(cup_end - first.doy + 1);
+        }
+        wtr.serialize(tower_rcrd)?;
+
+        start = end;
+    }
+    wtr.flush()?;
+    finish_temp_file(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Option<u32>, expected: u32) {
+        let actual = actual.unwrap();
+        assert!(
+            actual.abs_diff(expected) <= 1,
+            "day {actual} isn't day {expected}"
+        );
+    }
+
+    #[test]
+    fn transition_dates_of_a_symmetric_season() {
+        let config: Config =
+            toml::from_str("phenology_onset_fraction = 0.5\nphenology_maturity_fraction = 0.9")
+                .unwrap();
+        // rising around day 120 and falling around day 280 at the same rate, so the peak is at day 200
+        let params = [0.2, 0.8, 120.0, 0.1, 280.0, 0.1];
+        let points: Vec<(f64, f64, f64)> = (0..46)
+            .map(|i| {
+                let t = (1 + 8 * i) as f64;
+                (t, double_logistic_value(&params, t), 1.0)
+            })
+            .collect();
+        let rcrd = get_phenology_metrics("JP-Tkc", 2010, "ndvi", &points, &config);
+        // half the amplitude is at the midpoints
+        assert_near(rcrd.green_up, 120);
+        assert_near(rcrd.dormancy, 280);
+        // 0.9 of the amplitude is ln(9) / 0.1 = 22 days past the green-up midpoint and before the senescence one
+        assert_near(rcrd.maturity, 142);
+        assert_near(rcrd.senescence, 258);
+        assert_near(rcrd.peak_doy, 200);
+        assert_near(rcrd.season_length, 161);
+        assert!((rcrd.peak_value.unwrap() - 0.7996).abs() < 1e-3);
+        assert!((rcrd.amplitude.unwrap() - 0.5996).abs() < 1e-3);
+    }
+
+    #[test]
+    fn no_dates_without_a_fit() {
+        let config: Config = toml::from_str("").unwrap();
+        let points = [(1.0, 0.2, 1.0), (9.0, 0.3, 1.0), (17.0, 0.4, 0.0)];
+        let rcrd = get_phenology_metrics("JP-Tkc", 2010, "ndvi", &points, &config);
+        assert_eq!(rcrd.green_up, None);
+        assert_eq!(rcrd.peak_doy, None);
+    }
+}
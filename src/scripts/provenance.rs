@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::checkpoint::write_atomic;
+use crate::scripts::define_metadata::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+// a file as it was when it was looked up. size and modified are None when it doesn't exist
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FileInfo {
+    pub path: String,
+    pub size: Option<u64>,
+    pub modified: Option<String>,
+    // only with provenance_checksums in the config for the rasters, always for the flux file
+    pub sha256: Option<String>,
+}
+
+// one (dataset, date) looked up by check_if_modis_data_exists
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RasterLookup {
+    pub dataset: String,
+    pub product: String,
+    pub date: String,
+    pub data: FileInfo,
+    pub qc: FileInfo,
+    // why the files couldn't be used, None when both were found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing: Option<String>,
+}
+
+// lookups since the last take_raster_lookups, by (dataset, product, date) so a file used for several rows is listed once.
+// every reader passes it on to check_if_modis_data_exists through Rasters
+pub type RasterLookups = BTreeMap<(String, String, String), RasterLookup>;
+
+#[derive(Serialize)]
+struct Provenance<'a> {
+    tool: &'static str,
+    version: &'static str,
+    created: String,
+    // the file this sidecar is for, and the others written for the same sites from the same rasters
+    output: String,
+    written_with: Vec<String>,
+    site_codes: &'a [&'a str],
+    flux_file: &'a FileInfo,
+    config: &'a Config,
+    catalog: &'a [DatasetMetadata],
+    rasters: &'a [RasterLookup],
+    missing: &'a [RasterLookup],
+}
+
+fn get_sha256(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn get_file_info(path: &Path, checksum: bool) -> FileInfo {
+    let metadata = fs::metadata(path).ok();
+    FileInfo {
+        path: path.display().to_string(),
+        size: metadata.as_ref().map(|metadata| metadata.len()),
+        modified: metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339()),
+        sha256: if checksum {
+            get_sha256(path).ok()
+        } else {
+            None
+        },
+    }
+}
+
+// called by check_if_modis_data_exists for every lookup, found or not
+pub fn record_raster_lookup(
+    raster_lookups: &mut RasterLookups,
+    dm: &DatasetMetadata,
+    date: &str,
+    data_path: &Path,
+    qc_path: &Path,
+    missing: Option<&'static str>,
+) {
+    let key = (dm.dataset.clone(), dm.product.clone(), date.to_string());
+    if raster_lookups.contains_key(&key) {
+        return;
+    }
+    raster_lookups.insert(
+        key,
+        RasterLookup {
+            dataset: dm.dataset.clone(),
+            product: dm.product.clone(),
+            date: date.to_string(),
+            data: get_file_info(data_path, false),
+            qc: get_file_info(qc_path, false),
+            missing: missing.map(String::from),
+        },
+    );
+}
+
+// lookups recorded since the last call, e.g. everything read for one site
+pub fn take_raster_lookups(raster_lookups: &mut RasterLookups) -> Vec<RasterLookup> {
+    std::mem::take(raster_lookups).into_values().collect()
+}
+
+// the contents of the sidecar of an earlier output, None when it has none or it can't be read
+fn read_provenance<T: for<'de> Deserialize<'de>>(output_path: &Path) -> Option<T> {
+    let mut sidecar_path = output_path.as_os_str().to_owned();
+    sidecar_path.push(".provenance.json");
+    fs::read_to_string(sidecar_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+}
+
+// the lookups listed in the sidecar of an earlier output, found or not. empty when it has no sidecar
+pub fn read_provenance_lookups(output_path: &Path) -> Vec<RasterLookup> {
+    #[derive(Deserialize)]
+    struct Lookups {
+        rasters: Vec<RasterLookup>,
+        missing: Vec<RasterLookup>,
+    }
+    match read_provenance::<Lookups>(output_path) {
+        Some(lookups) => lookups.rasters.into_iter().chain(lookups.missing).collect(),
+        None => vec![],
+    }
+}
+
+// the config an earlier output was written with, from its sidecar
+pub fn read_provenance_config(output_path: &Path) -> Option<serde_json::Value> {
+    #[derive(Deserialize)]
+    struct ProvenanceConfig {
+        config: serde_json::Value,
+    }
+    read_provenance::<ProvenanceConfig>(output_path).map(|provenance| provenance.config)
+}
+
+// metadata of every dataset the config reads, with the config catalog applied
+fn get_active_catalog(config: &Config) -> Vec<DatasetMetadata> {
+    get_active_dataset_names(config)
+        .iter()
+        .map(|dataset_name| get_catalog_metadata(dataset_name, config))
+        .collect()
+}
+
+// Write {output}.provenance.json next to each of the outputs written from the same rasters (the main table, daily,
+// phenology, comparison): the tool version, config, dataset catalog, flux file, and every raster looked up for them
+// with its size and modification time, or why it was missing
+pub fn write_provenance(
+    outputs: &[PathBuf],
+    site_codes: &[&str],
+    flux_file: &FileInfo,
+    mut raster_lookups: Vec<RasterLookup>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if outputs.is_empty() {
+        return Ok(());
+    }
+    // the sites of a merged file share rasters
+    raster_lookups
+        .sort_by(|a, b| (&a.dataset, &a.product, &a.date).cmp(&(&b.dataset, &b.product, &b.date)));
+    raster_lookups
+        .dedup_by(|a, b| (&a.dataset, &a.product, &a.date) == (&b.dataset, &b.product, &b.date));
+    let (mut rasters, missing): (Vec<RasterLookup>, Vec<RasterLookup>) = raster_lookups
+        .into_iter()
+        .partition(|raster_lookup| raster_lookup.missing.is_none());
+    if config.provenance_checksums {
+        for raster in rasters.iter_mut() {
+            raster.data.sha256 = get_sha256(Path::new(&raster.data.path)).ok();
+            raster.qc.sha256 = get_sha256(Path::new(&raster.qc.path)).ok();
+        }
+    }
+    let created = Utc::now().to_rfc3339();
+    let catalog = get_active_catalog(config);
+    for output_path in outputs {
+        let provenance = Provenance {
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            created: created.clone(),
+            output: output_path.display().to_string(),
+            written_with: outputs
+                .iter()
+                .filter(|output| *output != output_path)
+                .map(|output| output.display().to_string())
+                .collect(),
+            site_codes,
+            flux_file,
+            config,
+            catalog: &catalog,
+            rasters: &rasters,
+            missing: &missing,
+        };
+        let mut sidecar_path = output_path.as_os_str().to_owned();
+        sidecar_path.push(".provenance.json");
+        write_atomic(
+            Path::new(&sidecar_path),
+            serde_json::to_string_pretty(&provenance)?.as_bytes(),
+        )?;
+    }
+    Ok(())
+}
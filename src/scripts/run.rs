@@ -1,14 +1,42 @@
+use crate::config::{load_config, Config, OutputFormat, OutputLayout};
 use crate::data::*;
+use crate::scripts::checkpoint::{
+    add_checkpoint_row, finish_temp_file, get_temp_path, load_checkpoint, read_checkpoint_rows,
+    remove_checkpoint_rows, save_checkpoint, save_site_checkpoint, SiteCheckpoint,
+};
+use crate::scripts::combine_sensors::{get_sensor_data, write_sensor_comparison_output};
+use crate::scripts::daily_output::{collect_composite_row, write_daily_output, CompositeRow};
 use crate::scripts::define_metadata::*;
-use crate::scripts::get_modis_data::get_modis_data;
+use crate::scripts::disturbance::{carry_burn_date, get_burn_data, get_snow_data};
+use crate::scripts::extraction_cache::{close_extraction_cache, open_extraction_cache};
+use crate::scripts::get_modis_data::Rasters;
+use crate::scripts::inventory::inventory;
+use crate::scripts::land_cover::{get_land_cover_values, set_land_cover_values, LandCoverValues};
+use crate::scripts::long_format::get_long_records;
+use crate::scripts::netcdf_output::write_netcdf_output;
+use crate::scripts::output_columns::{get_column_value, get_output_columns};
+use crate::scripts::parquet_output::{
+    get_long_parquet_schema, get_long_record_batch, get_parquet_schema, get_record_batch,
+    open_parquet_writer,
+};
+use crate::scripts::phenology::write_phenology_output;
+use crate::scripts::provenance::{
+    get_file_info, read_provenance_lookups, record_raster_lookup, take_raster_lookups,
+    write_provenance, RasterLookup, RasterLookups,
+};
+use crate::scripts::smoothing::smooth_site_rows;
+use crate::scripts::spectral_indices::{get_derived_index_data, get_derived_indices};
+use crate::scripts::state_flags::get_state_flag_data;
+use crate::scripts::update::{check_update_config, get_update_dates, read_output_rows};
 use chrono::prelude::*;
 use csv::{Position, ReaderBuilder, WriterBuilder};
 use std::{
+    collections::BTreeMap,
     env,
     error::Error,
     ffi::OsString,
+    fs,
     fs::File,
-    fs::{self, remove_file},
     path::{Path, PathBuf},
     str,
     time::Instant,
@@ -16,10 +44,39 @@ use std::{
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     // MODIS is the name of the nasa sensor used to collect the binary data I'm using
-    let modis_dir = "/modis/ORG/binary_data";
-    let output_dir = "./output";
     let file_path = get_first_arg()?;
-    let file = File::open(file_path)?;
+    // `update flux.csv [config.toml]` goes over the sites that have an output too, but only reads the rows
+    // that are new or that a new or changed composite covers, see update
+    let update = file_path == "update";
+    let (file_path, config_path) = if update {
+        match env::args_os().nth(2) {
+            None => return Err(From::from("expected fluxnet data file path after update")),
+            Some(file_path) => (file_path, env::args_os().nth(3)),
+        }
+    } else {
+        (file_path, env::args_os().nth(2))
+    };
+    let config = load_config(config_path)?;
+    // `inventory [config.toml]` only checks the archive, no flux file is read
+    if file_path == "inventory" {
+        return inventory(&config);
+    }
+    // parsed up front so a bad band_math expression stops the run before any site is processed
+    let derived_indices = get_derived_indices(&config)?;
+    // same for a misspelled output column
+    get_output_columns(&config)?;
+    if update {
+        check_update_config(&config)?;
+    }
+    // the lookups for the provenance sidecars and the extraction cache, passed to everything that reads the rasters
+    let mut rasters = Rasters {
+        cache: open_extraction_cache(&config)?,
+        ..Default::default()
+    };
+    let output_dir = config.output_dir.as_str();
+    let file = File::open(&file_path)?;
+    // hashed once, it goes in every provenance sidecar
+    let flux_file = get_file_info(Path::new(&file_path), true);
     let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
 
     // A site code is an id for a tower. I'm getting the locations of the towers
@@ -47,6 +104,43 @@ pub fn run() -> Result<(), Box<dyn Error>> {
     if !Path::new(output_dir).is_dir() {
         fs::create_dir(output_dir)?;
     }
+    // how far each site got in earlier runs with this config
+    let mut checkpoint = load_checkpoint(&flux_file, update, &config)?;
+
+    // with merge_sites parquet output goes to one file for all sites, otherwise one file per site in site_code={site} directories
+    let parquet_schema = match config.output_layout {
+        OutputLayout::Wide => get_parquet_schema(&config)?,
+        OutputLayout::Long => get_long_parquet_schema(),
+    };
+    let merged_file_str = match config.output_format {
+        OutputFormat::Parquet => format!("{}/asia_flux_modis.parquet", output_dir),
+        _ => format!("{}/asia_flux_modis.csv", output_dir),
+    };
+    let mut merged_parquet_wtr =
+        if config.output_format == OutputFormat::Parquet && config.merge_sites {
+            Some(open_parquet_writer(
+                &get_temp_path(Path::new(&merged_file_str)),
+                &parquet_schema,
+            )?)
+        } else {
+            None
+        };
+    // csv output for all sites in one file
+    let mut merged_csv_wtr = if config.output_format == OutputFormat::Csv && config.merge_sites {
+        Some(
+            WriterBuilder::new()
+                .flexible(false)
+                .from_path(get_temp_path(Path::new(&merged_file_str)))?,
+        )
+    } else {
+        None
+    };
+    let is_merged = merged_parquet_wtr.is_some() || merged_csv_wtr.is_some();
+    // the merged file gets one provenance sidecar for all sites once it is closed
+    let mut merged_site_codes: Vec<&str> = vec![];
+    let mut merged_raster_lookups: Vec<RasterLookup> = vec![];
+    // the merged csv only gets the header before its first site
+    let mut merged_csv_header_written = false;
 
     for (i, site_code) in site_codes_str.iter().enumerate() {
         // site_codes_str includes header "SiteCode", so skip that one
@@ -59,24 +153,39 @@ pub fn run() -> Result<(), Box<dyn Error>> {
         println!("SITE {i}/{}: {}", site_codes_str.len(), site_code);
 
         // file for new csv. each site gets its own file
-        let site_file_str = format!("{}/{}.csv", output_dir, site_code);
+        let site_file_str = match config.output_format {
+            OutputFormat::Csv => format!("{}/{}.csv", output_dir, site_code),
+            OutputFormat::Parquet => format!(
+                "{}/site_code={}/{}.parquet",
+                output_dir, site_code, site_code
+            ),
+            OutputFormat::Netcdf => format!("{}/{}.nc", output_dir, site_code),
+        };
         let site_file_path = Path::new(&site_file_str);
 
-        // skip previusly processed sites
-        if site_file_path.exists() {
-            // remove_file(site_file_path)?;
-            continue;
+        // skip sites finished by an earlier run. the merged files are rewritten every run, so their sites are read back from the checkpoint
+        let mut site_checkpoint = checkpoint
+            .sites
+            .get(*site_code)
+            .cloned()
+            .unwrap_or_default();
+        if site_checkpoint.complete && !is_merged {
+            if site_file_path.exists() {
+                continue;
+            }
+            // the output was removed since
+            site_checkpoint = SiteCheckpoint::default();
+        }
+        if site_checkpoint.rows == 0 && site_file_path.exists() && !is_merged && !update {
+            println!("{site_file_str} isn't in the checkpoint, it may be incomplete so the site is processed again");
         }
 
-        // initialize csv writer
-        let mut wtr = WriterBuilder::new()
-            .flexible(false)
-            .from_path(site_file_path)?;
-
-        // NewRecord struct. Each field represents on column of new csv file.
+        // NewRecord struct. its columns are in get_column_descriptors.
         // Represents line which will be written to new csv.
-        let mut rcrd = NewRecord::default();
-        rcrd.site_code = site_code;
+        let mut rcrd = NewRecord {
+            site_code,
+            ..Default::default()
+        };
 
         // Start at beginning of file for each site code
         rdr.seek(Position::new())?;
@@ -99,10 +208,10 @@ pub fn run() -> Result<(), Box<dyn Error>> {
         for result in rdr.records() {
             let record = result?;
             if &record.get(3).unwrap() == site_code {
-                rcrd.lat = record.get(5).unwrap().to_string();
-                rcrd.lon = record.get(6).unwrap().to_string();
-                rcrd.syear = record.get(8).unwrap().to_string();
-                rcrd.eyear = record.get(9).unwrap().to_string();
+                rcrd.lat = parse_site_value(site_code, "LAT", record.get(5).unwrap())?;
+                rcrd.lon = parse_site_value(site_code, "LON", record.get(6).unwrap())?;
+                rcrd.syear = parse_site_value(site_code, "SYEAR", record.get(8).unwrap())?;
+                rcrd.eyear = parse_site_value(site_code, "EYEAR", record.get(9).unwrap())?;
                 //stop iterating as they do not change over time
                 break;
             }
@@ -111,126 +220,502 @@ pub fn run() -> Result<(), Box<dyn Error>> {
         // go to csv line where site data begins
         rdr.seek(pos)?;
 
-        // Each new csv file will start at 2000 and go through 2020.
-        for year in 2000..=2020 {
+        // rows that aren't read again with the rasters read for them: the rows saved by an earlier run that stopped
+        // part way through the site, and with update the rows of the existing output no new or changed composite covers
+        let mut kept_rows: BTreeMap<NaiveDate, (NewRecord, Vec<RasterLookup>)> = BTreeMap::new();
+        for checkpoint_row in read_checkpoint_rows(site_code, site_checkpoint.rows, &config)? {
+            let rcrd = checkpoint_row.record;
+            let row_date = NaiveDate::from_yo_opt(rcrd.year, rcrd.doy).unwrap();
+            kept_rows.insert(row_date, (rcrd, checkpoint_row.raster_lookups));
+        }
+        if let Some(last_date) = site_checkpoint.last_date {
+            println!(
+                "{site_code}: {} rows up to {last_date} from the checkpoint",
+                kept_rows.len()
+            );
+        }
+        // the lookups of the kept output rows are in its sidecar, they go after the ones read now so those win
+        let mut previous_raster_lookups: Vec<RasterLookup> = vec![];
+        if update {
+            let output_rows = read_output_rows(site_code, site_file_path, &config)?;
+            let update_dates = get_update_dates(site_code, &output_rows, site_file_path, &config)?;
+            let keeps_rows = update_dates.len() < output_rows.len();
+            for (row_date, rcrd) in output_rows {
+                if !update_dates.contains(&row_date) {
+                    kept_rows.entry(row_date).or_insert((rcrd, vec![]));
+                }
+            }
+            if keeps_rows {
+                previous_raster_lookups = read_provenance_lookups(site_file_path);
+            }
+        }
+
+        // most recent burn seen in the tower window so far, carried forward through the site's rows
+        let mut last_burn_date: Option<NaiveDate> = None;
+        // land cover of a year, read with the first row of the year that is read from the rasters
+        let mut land_cover: Option<(i32, LandCoverValues)> = None;
+
+        // rows are kept until the site is done, smoothing and the daily output need the whole series of the site
+        let mut site_rows: Vec<NewRecord> = vec![];
+        // every raster read for the site's rows, for the provenance sidecar
+        let mut site_raster_lookups: Vec<RasterLookup> = vec![];
+
+        // rows are written from the start to the end date in the config (2000 - 2020 by default),
+        // or only around the tower's own years in tower period mode
+        let syear = rcrd.syear;
+        let eyear = rcrd.eyear;
+        let mut first_year = config.start_date.year();
+        let mut last_year = config.end_date.year();
+        if config.tower_period_only {
+            first_year = first_year.max(syear - config.tower_period_margin);
+            last_year = last_year.min(eyear + config.tower_period_margin);
+        }
+
+        // the input csv has one line per 8 days from the start year, so the loop starts early enough
+        // to step over tower years before the first written row
+        for year in first_year.min(syear)..=last_year {
             // add year to new csv record
             rcrd.year = year;
             // every 8 days
-            for doy in (1..=361).step_by(8) {
+            for doy in get_row_doys() {
                 println!("{year}.{doy}");
                 // add doy to new csv record
                 rcrd.doy = doy;
                 // once year reaches the year that the input csv already has data for, write that data to the new record
-                if year >= rcrd.syear.parse::<i32>().unwrap()
-                    && year <= rcrd.eyear.parse::<i32>().unwrap()
-                {
+                if year >= syear && year <= eyear {
                     let record = rdr.records().next().unwrap()?;
-                    rcrd.solar_radiation = record.get(13).unwrap().to_string();
-                    rcrd.air_temperature = record.get(14).unwrap().to_string();
-                    rcrd.vpd = record.get(15).unwrap().to_string();
-                    rcrd.sensible_heat = record.get(16).unwrap().to_string();
-                    rcrd.evapotranspiration = record.get(17).unwrap().to_string();
-                    rcrd.respiration = record.get(18).unwrap().to_string();
-                    rcrd.nee = record.get(19).unwrap().to_string();
-                    rcrd.gpp = record.get(20).unwrap().to_string();
+                    let flux_value = |column: usize| {
+                        parse_flux_value(site_code, year, doy, record.get(column).unwrap(), &config)
+                    };
+                    rcrd.solar_radiation = flux_value(13);
+                    rcrd.air_temperature = flux_value(14);
+                    rcrd.vpd = flux_value(15);
+                    rcrd.sensible_heat = flux_value(16);
+                    rcrd.evapotranspiration = flux_value(17);
+                    rcrd.respiration = flux_value(18);
+                    rcrd.nee = flux_value(19);
+                    rcrd.gpp = flux_value(20);
                 } else {
                     // years that fall outside of start or end year will have empty data for these columns
-                    rcrd.solar_radiation = "".to_string();
-                    rcrd.air_temperature = "".to_string();
-                    rcrd.vpd = "".to_string();
-                    rcrd.sensible_heat = "".to_string();
-                    rcrd.evapotranspiration = "".to_string();
-                    rcrd.respiration = "".to_string();
-                    rcrd.nee = "".to_string();
-                    rcrd.gpp = "".to_string();
+                    rcrd.solar_radiation = None;
+                    rcrd.air_temperature = None;
+                    rcrd.vpd = None;
+                    rcrd.sensible_heat = None;
+                    rcrd.evapotranspiration = None;
+                    rcrd.respiration = None;
+                    rcrd.nee = None;
+                    rcrd.gpp = None;
+                }
+                // rows outside of the study period are only stepped through to keep the input csv in line
+                let row_date = NaiveDate::from_yo_opt(year, doy).unwrap();
+                if year < first_year || row_date < config.start_date || row_date > config.end_date {
+                    continue;
+                }
+                // kept rows only get the flux values and the days since burn brought up to date
+                if let Some((mut kept_rcrd, raster_lookups)) = kept_rows.remove(&row_date) {
+                    copy_flux_values(&mut kept_rcrd, &rcrd);
+                    carry_burn_date(&mut kept_rcrd, &mut last_burn_date);
+                    if site_checkpoint
+                        .last_date
+                        .is_none_or(|last_date| row_date > last_date)
+                    {
+                        add_checkpoint_row(
+                            &mut checkpoint,
+                            &mut site_checkpoint,
+                            &kept_rcrd,
+                            &raster_lookups,
+                            &config,
+                        )?;
+                    }
+                    site_raster_lookups.extend(raster_lookups);
+                    site_rows.push(kept_rcrd);
+                    continue;
                 }
                 // a dataset name represents one set of binary files. Each one measure something different (temp/vegetation level/etc)
-                for dataset_name in get_dataset_names() {
-                    // get descriptive data about the dataset binary files
-                    let dm = get_dataset_metadata(&dataset_name);
-                    // check if the corresponding dataset file exists for the corresponding date, return the data file path and the quality control file path
-                    // both are binary files. QC just shows if a pixel in that location is reliable or not. The data file has the actual measured value. I have to read both.
-                    let data_qc_paths = check_if_modis_data_exists(year, doy, modis_dir, &dm);
-                    match data_qc_paths {
-                        Ok(data_qc_paths) => {
-                            // GET THE MODIS DATA FROM BINARY FILES
-                            // WRITE DATA TO rcrd
-                            rcrd = get_modis_data(rcrd, dm, data_qc_paths);
+                for dataset_name in get_dataset_names()
+                    .into_iter()
+                    .chain(get_catalog_dataset_names(&config))
+                {
+                    // GET THE MODIS DATA FROM BINARY FILES
+                    // WRITE DATA TO rcrd
+                    // with a terra/aqua combination set in the config this reads both sensors and picks or averages them
+                    rcrd = get_sensor_data(rcrd, &dataset_name, year, doy, &config, &mut rasters);
+                }
+                // when and from what angle the composites were observed, to match them to tower days
+                if config.extract_acquisition_layers {
+                    for dataset_name in get_acquisition_layer_names() {
+                        rcrd =
+                            get_sensor_data(rcrd, &dataset_name, year, doy, &config, &mut rasters);
+                    }
+                }
+                // indices from the nadir reflectance bands, computed pixel by pixel
+                rcrd = get_derived_index_data(
+                    rcrd,
+                    &derived_indices,
+                    year,
+                    doy,
+                    &config,
+                    &mut rasters,
+                );
+                // cloud, cloud shadow and snow flags from the surface reflectance state layer
+                for dataset_name in get_state_flag_names() {
+                    rcrd =
+                        get_state_flag_data(rcrd, &dataset_name, year, doy, &config, &mut rasters);
+                }
+                // snow and fire flags for the composite
+                rcrd = get_snow_data(rcrd, year, doy, &config, &mut rasters);
+                rcrd = get_burn_data(rcrd, year, doy, &mut last_burn_date, &config, &mut rasters);
+                // land cover is annual, every row of a year gets the same values
+                if land_cover
+                    .as_ref()
+                    .is_none_or(|(land_cover_year, _)| *land_cover_year != year)
+                {
+                    let tower_entry_data = TowerEntryData {
+                        year,
+                        doy: 1,
+                        lat: rcrd.lat,
+                        lon: rcrd.lon,
+                    };
+                    land_cover = Some((
+                        year,
+                        get_land_cover_values(&tower_entry_data, year, &config, &mut rasters),
+                    ));
+                }
+                if let Some((_, land_cover_values)) = &land_cover {
+                    rcrd = set_land_cover_values(rcrd, land_cover_values);
+                }
+                // saved before the next row is read so a run that stops carries on from here
+                let raster_lookups = take_raster_lookups(&mut rasters.lookups);
+                add_checkpoint_row(
+                    &mut checkpoint,
+                    &mut site_checkpoint,
+                    &rcrd,
+                    &raster_lookups,
+                    &config,
+                )?;
+                site_raster_lookups.extend(raster_lookups);
+                site_rows.push(rcrd.clone());
+            }
+        }
+        // optional csv comparing MODIS and VIIRS values for the same dates, from the values before smoothing
+        write_sensor_comparison_output(site_code, &site_rows, &config)?;
+        let site_rows = smooth_site_rows(site_rows, &config);
+        let long_records = match config.output_layout {
+            OutputLayout::Wide => vec![],
+            OutputLayout::Long => get_long_records(site_code, &site_rows, &config)?,
+        };
+        match config.output_format {
+            OutputFormat::Csv => {
+                // initialize csv writer
+                let write_header = merged_csv_wtr.is_none() || !merged_csv_header_written;
+                let mut site_wtr;
+                let wtr = match merged_csv_wtr.as_mut() {
+                    Some(merged_csv_wtr) => merged_csv_wtr,
+                    None => {
+                        site_wtr = WriterBuilder::new()
+                            .flexible(false)
+                            .from_path(get_temp_path(site_file_path))?;
+                        &mut site_wtr
+                    }
+                };
+                match config.output_layout {
+                    OutputLayout::Wide => {
+                        // columns from the catalog and config, see get_output_columns
+                        let columns = get_output_columns(&config)?;
+                        if write_header {
+                            wtr.write_record(columns.iter().map(|column| &column.name))?;
                         }
-                        Err(_e) => {
-                            // println!("{dataset_name}: {e}")
+                        for rcrd in &site_rows {
+                            // Write record to csv file. missing values are empty
+                            wtr.write_record(columns.iter().map(|column| {
+                                get_column_value(rcrd, column)
+                                    .map_or(String::new(), |value| value.to_string())
+                            }))?;
+                        }
+                    }
+                    OutputLayout::Long => {
+                        for long_record in &long_records {
+                            wtr.serialize(long_record)?;
                         }
                     }
-                }
-                // Write record to csv file.
-                match wtr.serialize(&rcrd) {
-                    Ok(_res) => {}
-                    Err(error) => panic!("Problem writing record: {error}"),
                 }
                 wtr.flush()?;
+                merged_csv_header_written = true;
+                if !is_merged {
+                    finish_temp_file(site_file_path)?;
+                }
             }
+            OutputFormat::Parquet => {
+                let batch = match config.output_layout {
+                    OutputLayout::Wide => get_record_batch(&site_rows, &parquet_schema, &config)?,
+                    OutputLayout::Long => get_long_record_batch(&long_records, &parquet_schema)?,
+                };
+                match merged_parquet_wtr.as_mut() {
+                    Some(parquet_wtr) => {
+                        parquet_wtr.write(&batch)?;
+                        parquet_wtr.flush()?;
+                    }
+                    None => {
+                        fs::create_dir_all(site_file_path.parent().unwrap())?;
+                        let mut parquet_wtr =
+                            open_parquet_writer(&get_temp_path(site_file_path), &parquet_schema)?;
+                        parquet_wtr.write(&batch)?;
+                        parquet_wtr.close()?;
+                        finish_temp_file(site_file_path)?;
+                    }
+                }
+            }
+            OutputFormat::Netcdf => {
+                write_netcdf_output(site_code, &site_rows, site_file_path, &config)?;
+            }
+        }
+        let mut composite_rows: Vec<CompositeRow> = vec![];
+        if config.daily_interpolation.is_some() {
+            for row in &site_rows {
+                let row_date = NaiveDate::from_yo_opt(row.year, row.doy).unwrap();
+                composite_rows.push(collect_composite_row(row, row_date, &config));
+            }
+        }
+        write_daily_output(site_code, &composite_rows, &config)?;
+        write_phenology_output(site_code, &site_rows, &config)?;
+
+        // files written for the site besides the main output, from the same rasters
+        let mut site_outputs = vec![];
+        if config.write_sensor_comparison {
+            site_outputs.push(PathBuf::from(format!(
+                "{}/{}_modis_viirs.csv",
+                output_dir, site_code
+            )));
+        }
+        if config.daily_interpolation.is_some() {
+            site_outputs.push(PathBuf::from(format!(
+                "{}/{}_daily.csv",
+                output_dir, site_code
+            )));
+        }
+        if config.write_phenology {
+            site_outputs.push(PathBuf::from(format!(
+                "{}/{}_phenology.csv",
+                output_dir, site_code
+            )));
+        }
+        site_raster_lookups.extend(previous_raster_lookups);
+        if is_merged {
+            merged_site_codes.push(site_code);
+            merged_raster_lookups.extend(site_raster_lookups.iter().cloned());
+        } else {
+            site_outputs.insert(0, site_file_path.to_path_buf());
+        }
+        // every output of the site gets its own sidecar, the merged file gets its own once it is closed
+        write_provenance(
+            &site_outputs,
+            &[site_code],
+            &flux_file,
+            site_raster_lookups,
+            &config,
+        )?;
+        // the site is done once all of its outputs are written. for the merged files its rows are kept until they are closed
+        site_checkpoint.complete = true;
+        save_site_checkpoint(&mut checkpoint, site_code, &site_checkpoint, &config)?;
+        if !is_merged {
+            remove_checkpoint_rows(site_code, &config)?;
         }
         println!("{:?}", instant.elapsed().as_secs() / 60);
     }
+    if let Some(parquet_wtr) = merged_parquet_wtr {
+        parquet_wtr.close()?;
+    }
+    if let Some(mut csv_wtr) = merged_csv_wtr {
+        csv_wtr.flush()?;
+    }
+    if is_merged {
+        finish_temp_file(Path::new(&merged_file_str))?;
+        write_provenance(
+            &[PathBuf::from(&merged_file_str)],
+            &merged_site_codes,
+            &flux_file,
+            merged_raster_lookups,
+            &config,
+        )?;
+        // the next run writes the merged file again from the start
+        for site_code in &merged_site_codes {
+            remove_checkpoint_rows(site_code, &config)?;
+            checkpoint.sites.remove(*site_code);
+        }
+        save_checkpoint(&checkpoint, &config)?;
+    }
+    close_extraction_cache(rasters.cache);
     Ok(())
 }
 
-fn check_if_modis_data_exists(
+// the site and flux columns of rcrd, for a row that isn't read again
+fn copy_flux_values(kept_rcrd: &mut NewRecord, rcrd: &NewRecord) {
+    kept_rcrd.lat = rcrd.lat;
+    kept_rcrd.lon = rcrd.lon;
+    kept_rcrd.syear = rcrd.syear;
+    kept_rcrd.eyear = rcrd.eyear;
+    kept_rcrd.solar_radiation = rcrd.solar_radiation;
+    kept_rcrd.air_temperature = rcrd.air_temperature;
+    kept_rcrd.vpd = rcrd.vpd;
+    kept_rcrd.sensible_heat = rcrd.sensible_heat;
+    kept_rcrd.evapotranspiration = rcrd.evapotranspiration;
+    kept_rcrd.respiration = rcrd.respiration;
+    kept_rcrd.nee = rcrd.nee;
+    kept_rcrd.gpp = rcrd.gpp;
+}
+
+// lat, lon and the tower years have to be there, the site can't be placed without them
+fn parse_site_value<T: str::FromStr>(
+    site_code: &str,
+    column: &str,
+    value: &str,
+) -> Result<T, Box<dyn Error>> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("{site_code}: {column} \"{value}\" is not a number").into())
+}
+
+// a flux value, or None for the missing value sentinels in the config. text that isn't a number
+// is printed so typos in the input don't go unnoticed
+fn parse_flux_value(
+    site_code: &str,
+    year: i32,
+    doy: u32,
+    value: &str,
+    config: &Config,
+) -> Option<f64> {
+    let value = value.trim();
+    let parsed = value.parse::<f64>().ok();
+    let missing = config.missing_values.iter().any(|missing_value| {
+        let missing_value = missing_value.trim();
+        missing_value == value
+            || parsed.is_some_and(|parsed| missing_value.parse::<f64>() == Ok(parsed))
+    });
+    if missing {
+        return None;
+    }
+    if parsed.is_none_or(|parsed| !parsed.is_finite()) {
+        println!("{site_code} {year}.{doy}: flux value \"{value}\" is not a number, left empty");
+        return None;
+    }
+    parsed
+}
+
+// the data file path and quality control file path of a dataset on a date, whether they exist or not.
+// the date in the file names is returned too
+pub fn get_modis_paths(
     year: i32,
     doy: u32,
     modis_dir: &str,
     dm: &DatasetMetadata,
-) -> Result<(PathBuf, PathBuf), &'static str> {
-    let naive_date = NaiveDate::from_yo_opt(year, doy).unwrap();
+) -> Result<(String, PathBuf, PathBuf), &'static str> {
+    // annual products only have one file per year, dated on the first day. monthly ones are dated on the first of the month
+    let (naive_date, _) = get_composite_window(year, doy, dm.composite_step);
     let date = naive_date.format("%Y.%m.%d");
 
-    let mut data_file_string = String::new();
-    let mut qc_file_string = String::new();
-
-    if ["NDVI", "EVI"].contains(&dm.dataset.as_str()) {
-        data_file_string = format!(
-            "{}.061.{}.1_km_16_days_{}.bsq",
-            dm.product,
-            date,
-            dm.dataset.as_str()
-        );
-        qc_file_string = format!("{}.061.{}.1_km_16_days_VI_Quality.bsq", dm.product, date);
-    } else if ["Lai", "Fpar", "LST_Day", "LST_Night"].contains(&dm.dataset.as_str()) {
-        data_file_string = format!(
-            "{}.061.{}.{}_{}.bsq",
-            dm.product, date, dm.dataset, dm.modis_size
-        );
-        qc_file_string = format!("{}.061.{}.{}.bsq", dm.product, date, dm.qc_name);
-    } else if dm.dataset.as_str().contains("Nadir") {
-        data_file_string = format!("{}.061.{}.{}.bsq", dm.product, date, dm.dataset);
-        qc_file_string = format!("{}.061.{}.{}.bsq", dm.product, date, dm.qc_name);
+    // every file is named {product}.{collection}.{date}.{layer}.bsq, the layer names differ a bit between products
+    let mut data_layer = String::new();
+    let mut qc_layer = dm.qc_name.clone();
+
+    if !dm.data_layer.is_empty() {
+        // datasets from the config catalog name their layer
+        data_layer = dm.data_layer.clone();
+    } else if dm.product.starts_with("VNP") {
+        // VIIRS datasets are named VNP_{layer} so they don't clash with the MODIS ones
+        let layer = dm.dataset.trim_start_matches("VNP_");
+        data_layer = match dm.product.as_str() {
+            "VNP13A1" => format!("500_m_16_days_{}", layer),
+            "VNP21A2" => format!("{}_1KM", layer),
+            _ => layer.to_string(),
+        };
+    } else if dm.product == "MOD17A2H" {
+        // named {layer}_MOD17A2H so they don't clash with the MOD17A2HGF ones, the layers have the same names
+        let layer = dm.dataset.trim_end_matches("_MOD17A2H");
+        data_layer = format!("{}_{}", layer, dm.modis_size);
+    } else if ["NDVI", "EVI", "composite_day_of_the_year"].contains(&dm.dataset.as_str()) {
+        data_layer = format!("1_km_16_days_{}", dm.dataset);
+        qc_layer = String::from("1_km_16_days_VI_Quality");
+    } else if [
+        "Lai",
+        "Fpar",
+        "Gpp",
+        "PsnNet",
+        "Npp",
+        "ET",
+        "LE",
+        "PET",
+        "PLE",
+        "LST_Day",
+        "LST_Night",
+    ]
+    .contains(&dm.dataset.as_str())
+    {
+        data_layer = format!("{}_{}", dm.dataset, dm.modis_size);
+    } else if dm.dataset.as_str().contains("Nadir")
+        || ["MCD12Q1", "MOD09A1", "MCD43A3", "MOD10A2", "MCD64A1"].contains(&dm.product.as_str())
+        || dm.dataset.contains("_view_")
+    {
+        data_layer = dm.dataset.clone();
     }
 
+    if data_layer.is_empty() {
+        return Err("No file name defined for dataset");
+    }
+
+    let collection = get_collection(&dm.product);
+    let product_dir = format!(
+        "{}/{}.{}/{}_org",
+        modis_dir, dm.product, collection, dm.modis_size
+    );
     let file_path_string = format!(
-        "{}/{}.061/{}_org/{}",
-        modis_dir, dm.product, dm.modis_size, data_file_string
+        "{}/{}.{}.{}.{}.bsq",
+        product_dir, dm.product, collection, date, data_layer
     );
     let qc_file_path_string = format!(
-        "{}/{}.061/{}_org/{}",
-        modis_dir, dm.product, dm.modis_size, qc_file_string,
+        "{}/{}.{}.{}.{}.bsq",
+        product_dir, dm.product, collection, date, qc_layer
     );
-    let file_path = PathBuf::from(&file_path_string);
-    let qc_file_path = PathBuf::from(&qc_file_path_string);
+    Ok((
+        date.to_string(),
+        PathBuf::from(&file_path_string),
+        PathBuf::from(&qc_file_path_string),
+    ))
+}
+
+// check if the corresponding dataset file exists for the corresponding date, return the data file path and the quality control file path
+// both are binary files. QC just shows if a pixel in that location is reliable or not. The data file has the actual measured value. I have to read both.
+pub fn check_if_modis_data_exists(
+    year: i32,
+    doy: u32,
+    modis_dir: &str,
+    dm: &DatasetMetadata,
+    raster_lookups: &mut RasterLookups,
+) -> Result<(PathBuf, PathBuf), &'static str> {
+    let (date, file_path, qc_file_path) = get_modis_paths(year, doy, modis_dir, dm)?;
     let paths_exist = (file_path.exists(), qc_file_path.exists());
 
-    match paths_exist {
-        (true, true) => {
-            let data_qc_paths = (file_path, qc_file_path);
-            Ok(data_qc_paths)
-        }
+    let result = match paths_exist {
+        (true, true) => Ok(()),
         (true, false) => Err("No QC file found"),
         (false, true) => Err("No data file found"),
         (false, false) => Err("No data file or QC file found"),
-    }
+    };
+    // every lookup goes in the provenance sidecar of the output
+    record_raster_lookup(
+        raster_lookups,
+        dm,
+        &date,
+        &file_path,
+        &qc_file_path,
+        result.err(),
+    );
+    result.map(|_| (file_path, qc_file_path))
 }
 
 /// Returns the first positional argument sent to this process. If there are no
-/// positional arguments, then this returns an error.
+/// positional arguments, then this returns an error. The optional second argument is a toml config file.
+/// A first argument of inventory checks the archive instead, see inventory. update is followed by the flux data file and config.
 fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
     match env::args_os().nth(1) {
         None => Err(From::from("expected fluxnet data file path, but got none")),
@@ -0,0 +1,441 @@
+use crate::config::{Config, SmoothingMethod};
+use crate::data::*;
+use crate::scripts::define_metadata::get_smoothed_dataset_names;
+use crate::scripts::get_modis_data::{get_modis_values, set_modis_smoothed};
+
+// solve a small dense system with gaussian elimination and partial pivoting.
+// None when the system is singular
+fn solve_linear_system(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (k, value) in a[row].iter_mut().enumerate().skip(col) {
+                *value -= factor * pivot_row[k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+// weighted savitzky-golay: a polynomial is fitted to the rows within half_window of each row, weighted by goodpix.
+// rows without a value have no weight, so the fit also fills them when there are enough values around
+pub fn savitzky_golay(
+    values: &[Option<f64>],
+    weights: &[f64],
+    half_window: usize,
+    order: usize,
+) -> Vec<Option<f64>> {
+    let n = values.len();
+    let mut smoothed = vec![None; n];
+    for (i, smoothed_value) in smoothed.iter_mut().enumerate() {
+        let first = i.saturating_sub(half_window);
+        let last = (i + half_window).min(n - 1);
+        let points: Vec<(f64, f64, f64)> = (first..=last)
+            .filter_map(|j| match values[j] {
+                Some(value) if weights[j] > 0.0 => Some((j as f64 - i as f64, value, weights[j])),
+                _ => None,
+            })
+            .collect();
+        if points.len() <= order {
+            continue;
+        }
+        // normal equations for the polynomial coefficients, the value at row i is the constant term
+        let mut a = vec![vec![0.0; order + 1]; order + 1];
+        let mut b = vec![0.0; order + 1];
+        for (x, y, w) in &points {
+            for r in 0..=order {
+                b[r] += w * y * x.powi(r as i32);
+                for (c, a_rc) in a[r].iter_mut().enumerate() {
+                    *a_rc += w * x.powi((r + c) as i32);
+                }
+            }
+        }
+        *smoothed_value = solve_linear_system(a, b).map(|coefficients| coefficients[0]);
+    }
+    smoothed
+}
+
+// weighted whittaker smoother with second order differences: (W + lambda D'D) z = W y.
+// the system is pentadiagonal and solved with a banded cholesky decomposition
+pub fn whittaker(values: &[Option<f64>], weights: &[f64], lambda: f64) -> Vec<Option<f64>> {
+    let n = values.len();
+    let weighted_count = (0..n)
+        .filter(|&i| values[i].is_some() && weights[i] > 0.0)
+        .count();
+    if weighted_count < 3 {
+        return vec![None; n];
+    }
+    let w: Vec<f64> = (0..n)
+        .map(|i| if values[i].is_some() { weights[i] } else { 0.0 })
+        .collect();
+
+    // band[i][k] is the matrix entry (i, i + k) for k = 0, 1, 2
+    let mut band = vec![[0.0; 3]; n];
+    for (i, band_row) in band.iter_mut().enumerate() {
+        band_row[0] = w[i];
+    }
+    // D'D for second differences, each difference row touches rows j, j + 1, j + 2 with 1, -2, 1
+    for j in 0..n.saturating_sub(2) {
+        let coefficients = [1.0, -2.0, 1.0];
+        for a in 0..3 {
+            for b in a..3 {
+                band[j + a][b - a] += lambda * coefficients[a] * coefficients[b];
+            }
+        }
+    }
+    let mut rhs: Vec<f64> = (0..n).map(|i| w[i] * values[i].unwrap_or(0.0)).collect();
+
+    // cholesky, band[i] becomes row i of the upper factor
+    for i in 0..n {
+        for k in 1..=2.min(i) {
+            let p = i - k;
+            let factor = band[p][k];
+            for m in 0..3 - k {
+                band[i][m] -= factor * band[p][k + m];
+            }
+        }
+        if band[i][0] <= 0.0 {
+            return vec![None; n];
+        }
+        let diagonal = band[i][0].sqrt();
+        band[i][0] = diagonal;
+        band[i][1] /= diagonal;
+        band[i][2] /= diagonal;
+    }
+    // forward substitution with the transposed factor, then back substitution
+    for i in 0..n {
+        for k in 1..=2.min(i) {
+            rhs[i] -= band[i - k][k] * rhs[i - k];
+        }
+        rhs[i] /= band[i][0];
+    }
+    for i in (0..n).rev() {
+        for k in 1..=2 {
+            if i + k < n {
+                rhs[i] -= band[i][k] * rhs[i + k];
+            }
+        }
+        rhs[i] /= band[i][0];
+    }
+    rhs.into_iter().map(Some).collect()
+}
+
+// double logistic curve [min, max, green-up midpoint, green-up rate, senescence midpoint, senescence rate]
+pub fn double_logistic_value(params: &[f64; 6], t: f64) -> f64 {
+    let [min, max, sos, green_up_rate, eos, senescence_rate] = *params;
+    min + (max - min)
+        * (1.0 / (1.0 + (-green_up_rate * (t - sos)).exp())
+            - 1.0 / (1.0 + (-senescence_rate * (t - eos)).exp()))
+}
+
+// weighted least squares fit of the double logistic curve with levenberg-marquardt.
+// points are (day of year, value, weight). None when there are too few points or the fit doesn't converge
+pub fn fit_double_logistic(points: &[(f64, f64, f64)]) -> Option<[f64; 6]> {
+    let points: Vec<&(f64, f64, f64)> = points.iter().filter(|(_, _, w)| *w > 0.0).collect();
+    if points.len() < 6 {
+        return None;
+    }
+    let (peak_t, max) = points
+        .iter()
+        .map(|(t, y, _)| (*t, *y))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    let min = points
+        .iter()
+        .map(|(_, y, _)| *y)
+        .fold(f64::INFINITY, f64::min);
+    let first_t = points[0].0;
+    let last_t = points[points.len() - 1].0;
+    let mut params = [
+        min,
+        max,
+        (first_t + peak_t) / 2.0,
+        0.1,
+        (peak_t + last_t) / 2.0,
+        0.1,
+    ];
+
+    let cost = |params: &[f64; 6]| -> f64 {
+        points
+            .iter()
+            .map(|(t, y, w)| w * (y - double_logistic_value(params, *t)).powi(2))
+            .sum()
+    };
+    let mut current_cost = cost(&params);
+    let mut damping = 1e-3;
+    for _ in 0..200 {
+        // numeric jacobian
+        let mut jtj = vec![vec![0.0; 6]; 6];
+        let mut jtr = vec![0.0; 6];
+        for (t, y, w) in &points {
+            let residual = y - double_logistic_value(&params, *t);
+            let mut gradient = [0.0; 6];
+            for (k, g) in gradient.iter_mut().enumerate() {
+                let step = 1e-6 * params[k].abs().max(1e-3);
+                let mut shifted = params;
+                shifted[k] += step;
+                *g = (double_logistic_value(&shifted, *t) - double_logistic_value(&params, *t))
+                    / step;
+            }
+            for r in 0..6 {
+                jtr[r] += w * gradient[r] * residual;
+                for c in 0..6 {
+                    jtj[r][c] += w * gradient[r] * gradient[c];
+                }
+            }
+        }
+        for (r, row) in jtj.iter_mut().enumerate() {
+            row[r] *= 1.0 + damping;
+        }
+        let Some(delta) = solve_linear_system(jtj, jtr) else {
+            damping *= 10.0;
+            continue;
+        };
+        let mut candidate = params;
+        for k in 0..6 {
+            candidate[k] += delta[k];
+        }
+        let candidate_cost = cost(&candidate);
+        if candidate_cost.is_finite() && candidate_cost < current_cost {
+            let improvement = current_cost - candidate_cost;
+            params = candidate;
+            current_cost = candidate_cost;
+            damping = (damping / 10.0).max(1e-9);
+            if improvement < 1e-10 {
+                break;
+            }
+        } else {
+            damping *= 10.0;
+            if damping > 1e10 {
+                break;
+            }
+        }
+    }
+    if params.iter().all(|p| p.is_finite()) {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+// double logistic fitted separately to each year of the series. years is the year of each row, doys the day of the year
+fn double_logistic(
+    values: &[Option<f64>],
+    weights: &[f64],
+    years: &[i32],
+    doys: &[u32],
+) -> Vec<Option<f64>> {
+    let mut smoothed = vec![None; values.len()];
+    let mut start = 0;
+    while start < values.len() {
+        let end = (start..values.len())
+            .find(|&i| years[i] != years[start])
+            .unwrap_or(values.len());
+        let points: Vec<(f64, f64, f64)> = (start..end)
+            .filter_map(|i| values[i].map(|value| (doys[i] as f64, value, weights[i])))
+            .collect();
+        if let Some(params) = fit_double_logistic(&points) {
+            for i in start..end {
+                smoothed[i] = Some(double_logistic_value(&params, doys[i] as f64));
+            }
+        }
+        start = end;
+    }
+    smoothed
+}
+
+// upper envelope smoothing: values below the savitzky-golay fit are assumed to be cloud or snow contaminated
+// and are raised to the fit before smoothing again
+fn upper_envelope(values: &[Option<f64>], weights: &[f64], config: &Config) -> Vec<Option<f64>> {
+    let mut working = values.to_vec();
+    let mut smoothed = savitzky_golay(
+        &working,
+        weights,
+        config.savitzky_golay_half_window,
+        config.savitzky_golay_order,
+    );
+    for _ in 0..config.upper_envelope_iterations {
+        for (value, fit) in working.iter_mut().zip(&smoothed) {
+            if let (Some(v), Some(f)) = (value.as_mut(), fit) {
+                if *v < *f {
+                    *v = *f;
+                }
+            }
+        }
+        smoothed = savitzky_golay(
+            &working,
+            weights,
+            config.savitzky_golay_half_window,
+            config.savitzky_golay_order,
+        );
+    }
+    smoothed
+}
+
+// Smooth the vegetation series of a site's rows with the method in the config.
+// every dataset gets a smoothed column and a filled column, which keeps the raw value and uses the smoothed one for gaps
+pub fn smooth_site_rows<'a>(mut rows: Vec<NewRecord<'a>>, config: &Config) -> Vec<NewRecord<'a>> {
+    let Some(method) = config.smoothing else {
+        return rows;
+    };
+    let years: Vec<i32> = rows.iter().map(|rcrd| rcrd.year).collect();
+    let doys: Vec<u32> = rows.iter().map(|rcrd| rcrd.doy).collect();
+    for dataset_name in get_smoothed_dataset_names() {
+        let (values, weights): (Vec<Option<f64>>, Vec<f64>) = rows
+            .iter()
+            .map(|rcrd| {
+                let (value, goodpix) = get_modis_values(rcrd, &dataset_name, config);
+                (value, goodpix.unwrap_or(0.0) as f64)
+            })
+            .unzip();
+        let smoothed = match method {
+            SmoothingMethod::SavitzkyGolay => savitzky_golay(
+                &values,
+                &weights,
+                config.savitzky_golay_half_window,
+                config.savitzky_golay_order,
+            ),
+            SmoothingMethod::Whittaker => whittaker(&values, &weights, config.whittaker_lambda),
+            SmoothingMethod::DoubleLogistic => double_logistic(&values, &weights, &years, &doys),
+            SmoothingMethod::UpperEnvelope => upper_envelope(&values, &weights, config),
+        };
+        rows = rows
+            .into_iter()
+            .zip(values.iter().zip(smoothed))
+            .map(|(rcrd, (value, smoothed))| {
+                let smoothed = smoothed.map(|s| (s * 10000.0).round() / 10000.0);
+                let filled = value.or(smoothed);
+                set_modis_smoothed(rcrd, &dataset_name, (smoothed, filled), config)
+            })
+            .collect();
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "{actual} isn't within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn savitzky_golay_matches_the_tabulated_coefficients() {
+        // 5 point quadratic coefficients are (-3, 12, 17, 12, -3) / 35, an impulse gives them back
+        let values = [0.0, 0.0, 1.0, 0.0, 0.0].map(Some);
+        let smoothed = savitzky_golay(&values, &[1.0; 5], 2, 2);
+        assert_close(smoothed[2].unwrap(), 17.0 / 35.0, 1e-12);
+        // a quadratic is reproduced, including the missing row
+        let mut values: Vec<Option<f64>> = (0..9)
+            .map(|i| Some(0.5 * (i * i) as f64 - 2.0 * i as f64 + 1.0))
+            .collect();
+        values[4] = None;
+        let smoothed = savitzky_golay(&values, &[1.0; 9], 3, 2);
+        for (i, value) in smoothed.iter().enumerate() {
+            assert_close(
+                value.unwrap(),
+                0.5 * (i * i) as f64 - 2.0 * i as f64 + 1.0,
+                1e-9,
+            );
+        }
+        // too few values around a row leave it empty
+        let values = [Some(1.0), None, None, None, Some(2.0)];
+        assert_eq!(savitzky_golay(&values, &[1.0; 5], 1, 2)[2], None);
+    }
+
+    #[test]
+    fn whittaker_matches_the_dense_solution() {
+        let values = [
+            Some(0.2),
+            Some(0.35),
+            None,
+            Some(0.6),
+            Some(0.4),
+            Some(0.7),
+            Some(0.65),
+        ];
+        let weights = [1.0, 0.5, 1.0, 1.0, 0.25, 1.0, 0.75];
+        let lambda = 2.0;
+        // (W + lambda D'D) z = W y built as a full matrix
+        let n = values.len();
+        let w: Vec<f64> = (0..n)
+            .map(|i| if values[i].is_some() { weights[i] } else { 0.0 })
+            .collect();
+        let mut a = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            a[i][i] = w[i];
+        }
+        for j in 0..n - 2 {
+            let coefficients = [1.0, -2.0, 1.0];
+            for r in 0..3 {
+                for c in 0..3 {
+                    a[j + r][j + c] += lambda * coefficients[r] * coefficients[c];
+                }
+            }
+        }
+        let b: Vec<f64> = (0..n).map(|i| w[i] * values[i].unwrap_or(0.0)).collect();
+        let expected = solve_linear_system(a, b).unwrap();
+        let smoothed = whittaker(&values, &weights, lambda);
+        for (value, expected) in smoothed.iter().zip(expected) {
+            assert_close(value.unwrap(), expected, 1e-12);
+        }
+    }
+
+    #[test]
+    fn whittaker_keeps_lines_and_needs_three_values() {
+        // second differences of a line are zero, so any lambda gives it back and fills the gaps on it
+        let mut values: Vec<Option<f64>> = (0..10).map(|i| Some(0.1 + 0.05 * i as f64)).collect();
+        values[3] = None;
+        values[7] = None;
+        for (i, value) in whittaker(&values, &[1.0; 10], 1000.0).iter().enumerate() {
+            assert_close(value.unwrap(), 0.1 + 0.05 * i as f64, 1e-9);
+        }
+        // lambda 0 gives back the values
+        let values = [Some(1.0), Some(3.0), Some(2.0), Some(5.0)];
+        let smoothed = whittaker(&values, &[1.0; 4], 0.0);
+        for (value, expected) in smoothed.iter().zip(values) {
+            assert_close(value.unwrap(), expected.unwrap(), 1e-12);
+        }
+        let values = [Some(1.0), None, Some(2.0), Some(4.0)];
+        assert_eq!(
+            whittaker(&values, &[1.0, 1.0, 0.0, 1.0], 1.0),
+            vec![None; 4]
+        );
+    }
+
+    #[test]
+    fn double_logistic_fit_recovers_the_curve() {
+        let params = [0.2, 0.8, 120.0, 0.1, 280.0, 0.08];
+        let points: Vec<(f64, f64, f64)> = (0..46)
+            .map(|i| {
+                let t = (1 + 8 * i) as f64;
+                (t, double_logistic_value(&params, t), 1.0)
+            })
+            .collect();
+        let fitted = fit_double_logistic(&points).unwrap();
+        for (t, y, _) in &points {
+            assert_close(double_logistic_value(&fitted, *t), *y, 1e-3);
+        }
+        assert_close(fitted[2], 120.0, 1.0);
+        assert_close(fitted[4], 280.0, 1.0);
+        // six weighted points are needed
+        assert_eq!(fit_double_logistic(&points[..5]), None);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::{set_modis_values, try_read_mesh, Rasters};
+use crate::scripts::run::check_if_modis_data_exists;
+
+// A parsed band math expression over the MCD43A4 nadir reflectance bands b1 - b7, e.g. "(b2 - b6) / (b2 + b6)".
+// supports + - * / ^, parentheses, numbers and the functions sqrt, abs, exp, ln, log10 and tanh
+pub enum BandMath {
+    Number(f64),
+    Band(usize),
+    Negate(Box<BandMath>),
+    Operation(char, Box<BandMath>, Box<BandMath>),
+    Function(String, Box<BandMath>),
+}
+
+impl BandMath {
+    // bands are reflectances indexed from 0 for b1
+    pub fn evaluate(&self, bands: &[f64; 7]) -> f64 {
+        match self {
+            BandMath::Number(x) => *x,
+            BandMath::Band(b) => bands[*b],
+            BandMath::Negate(x) => -x.evaluate(bands),
+            BandMath::Operation(op, a, b) => {
+                let (a, b) = (a.evaluate(bands), b.evaluate(bands));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+            BandMath::Function(name, x) => {
+                let x = x.evaluate(bands);
+                match name.as_str() {
+                    "sqrt" => x.sqrt(),
+                    "abs" => x.abs(),
+                    "exp" => x.exp(),
+                    "ln" => x.ln(),
+                    "log10" => x.log10(),
+                    _ => x.tanh(),
+                }
+            }
+        }
+    }
+
+    // band indexes the expression reads, so pixels are only masked by the qc of those bands
+    pub fn bands_used(&self, bands: &mut Vec<usize>) {
+        match self {
+            BandMath::Number(_) => {}
+            BandMath::Band(b) => {
+                if !bands.contains(b) {
+                    bands.push(*b);
+                }
+            }
+            BandMath::Negate(x) | BandMath::Function(_, x) => x.bands_used(bands),
+            BandMath::Operation(_, a, b) => {
+                a.bands_used(bands);
+                b.bands_used(bands);
+            }
+        }
+    }
+}
+
+// recursive descent parser over the characters of the expression with whitespace removed
+struct BandMathParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl BandMathParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // expression = term (("+" | "-") term)*
+    fn expression(&mut self) -> Result<BandMath, String> {
+        let mut left = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            left = BandMath::Operation(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    // term = unary (("*" | "/") unary)*
+    fn term(&mut self) -> Result<BandMath, String> {
+        let mut left = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            left = BandMath::Operation(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    // unary = "-" unary | atom ("^" unary)?
+    fn unary(&mut self) -> Result<BandMath, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(BandMath::Negate(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(BandMath::Operation(
+                '^',
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+        Ok(base)
+    }
+
+    // atom = number | band | function "(" expression ")" | "(" expression ")"
+    fn atom(&mut self) -> Result<BandMath, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expression()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number
+                    .parse()
+                    .map(BandMath::Number)
+                    .map_err(|_| format!("invalid number {number}"))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                if ["sqrt", "abs", "exp", "ln", "log10", "tanh"].contains(&name.as_str()) {
+                    self.expect('(')?;
+                    let argument = self.expression()?;
+                    self.expect(')')?;
+                    return Ok(BandMath::Function(name, Box::new(argument)));
+                }
+                match name.as_str() {
+                    "b1" | "b2" | "b3" | "b4" | "b5" | "b6" | "b7" => {
+                        Ok(BandMath::Band(name[1..].parse::<usize>().unwrap() - 1))
+                    }
+                    _ => Err(format!("unknown band or function {name}")),
+                }
+            }
+            Some(c) => Err(format!("unexpected character {c}")),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected {c} at position {}", self.pos))
+        }
+    }
+}
+
+pub fn parse_band_math(expression: &str) -> Result<BandMath, String> {
+    let mut parser = BandMathParser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+    let parsed = parser
+        .expression()
+        .map_err(|e| format!("band math \"{expression}\": {e}"))?;
+    if parser.pos != parser.chars.len() {
+        return Err(format!(
+            "band math \"{expression}\": unexpected character {}",
+            parser.chars[parser.pos]
+        ));
+    }
+    Ok(parsed)
+}
+
+// The built in indices and the band_math expression from the config, parsed once before the sites are processed.
+pub fn get_derived_indices(config: &Config) -> Result<Vec<(String, BandMath)>, String> {
+    if !config.derived_indices {
+        return Ok(vec![]);
+    }
+    let mut derived_indices = vec![];
+    for (name, expression) in get_derived_index_expressions() {
+        derived_indices.push((name, parse_band_math(&expression)?));
+    }
+    if let Some(expression) = &config.band_math_expression {
+        derived_indices.push(("band_math".to_string(), parse_band_math(expression)?));
+    }
+    Ok(derived_indices)
+}
+
+// Reads the MCD43A4 bands around the tower and computes each index per pixel before averaging over the window.
+// a pixel is masked when one of the bands the index uses is fill or has a BRDF quality above brdf_max_qc,
+// goodpix is the fraction of the window where all of those bands are full inversions (qc 0)
+pub fn get_derived_index_data<'a>(
+    mut rcrd: NewRecord<'a>,
+    derived_indices: &[(String, BandMath)],
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> NewRecord<'a> {
+    if derived_indices.is_empty() {
+        return rcrd;
+    }
+    let tower_entry_data = TowerEntryData {
+        year,
+        doy,
+        lat: rcrd.lat,
+        lon: rcrd.lon,
+    };
+
+    // (reflectance, qc) per band, None when the band's files are missing
+    let mut bands: Vec<Option<(Vec<f64>, Vec<u64>)>> = vec![];
+    for band in 1..=7 {
+        let dm = get_dataset_metadata(&format!("Nadir_Reflectance_Band{band}"));
+        bands.push(
+            match check_if_modis_data_exists(
+                year,
+                doy,
+                &config.modis_dir,
+                &dm,
+                &mut rasters.lookups,
+            ) {
+                Ok(data_qc_paths) => try_read_mesh(&dm, &tower_entry_data, data_qc_paths, rasters),
+                Err(_e) => None,
+            },
+        );
+    }
+
+    for (name, band_math) in derived_indices {
+        let mut used = vec![];
+        band_math.bands_used(&mut used);
+        let mut values = (None, None);
+        if !used.is_empty() && used.iter().all(|b| bands[*b].is_some()) {
+            let pixel_count = bands[used[0]].as_ref().unwrap().0.len();
+            let mut index_values = vec![];
+            let mut good_qc_count = 0;
+            for pixel in 0..pixel_count {
+                let mut reflectances = [f64::NAN; 7];
+                let mut masked = false;
+                let mut best_qc = true;
+                for b in &used {
+                    let (data, qc) = bands[*b].as_ref().unwrap();
+                    if data[pixel] == 32767.0 || qc[pixel] > config.brdf_max_qc {
+                        masked = true;
+                    }
+                    if qc[pixel] != 0 {
+                        best_qc = false;
+                    }
+                    reflectances[*b] = data[pixel] * 0.0001;
+                }
+                if !masked && best_qc {
+                    good_qc_count += 1;
+                }
+                let value = band_math.evaluate(&reflectances);
+                if !masked && value.is_finite() {
+                    index_values.push(value);
+                }
+            }
+            // same rule as find_mesh_values, more than half of the window masked gives no value
+            if !index_values.is_empty() && index_values.len() * 2 >= pixel_count {
+                let mean = index_values.iter().sum::<f64>() / index_values.len() as f64;
+                values = (
+                    Some((mean * 10000.0).round() / 10000.0),
+                    Some(good_qc_count as f32 / pixel_count as f32),
+                );
+            }
+        }
+        rcrd = set_modis_values(rcrd, name, values, config);
+    }
+    rcrd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BANDS: [f64; 7] = [0.1, 0.5, 0.05, 0.08, 0.3, 0.2, 0.4];
+
+    fn evaluate(expression: &str) -> f64 {
+        parse_band_math(expression).unwrap().evaluate(&BANDS)
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(evaluate("1 + 2 * 3 ^ 2"), 19.0);
+        assert_eq!(evaluate("8 - 4 - 2"), 2.0);
+        assert_eq!(evaluate("16 / 4 / 2"), 2.0);
+        // ^ is right associative and binds tighter than unary minus
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(evaluate("-2 ^ 2"), -4.0);
+        assert_eq!(evaluate("2 ^ -1"), 0.5);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+    }
+
+    #[test]
+    fn bands_and_functions() {
+        assert_eq!(evaluate("b1"), 0.1);
+        assert_eq!(evaluate("b7"), 0.4);
+        assert!((evaluate("(b2 - b1) / (b2 + b1)") - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(evaluate("sqrt(b2 * 0.5)"), 0.5);
+        assert_eq!(evaluate("abs(b1 - b2)"), 0.4);
+        assert_eq!(evaluate("ln(exp(2))"), 2.0);
+        assert_eq!(evaluate("log10(1000)"), 3.0);
+        assert_eq!(evaluate("tanh(0)"), 0.0);
+        let mut bands = vec![];
+        parse_band_math("(b2 - b6) / (b2 + b6) * 2")
+            .unwrap()
+            .bands_used(&mut bands);
+        assert_eq!(bands, vec![1, 5]);
+        // the built in indices all parse, and kNDVI is tanh(NDVI^2)
+        for (name, expression) in get_derived_index_expressions() {
+            assert!(parse_band_math(&expression).is_ok(), "{name}");
+        }
+        assert!(
+            (evaluate("tanh(((b2 - b1) / (b2 + b1))^2)") - (4.0f64 / 9.0).tanh()).abs() < 1e-12
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |expression: &str| parse_band_math(expression).err().unwrap();
+        assert!(error("b8 + b1").contains("unknown band or function b8"));
+        assert!(error("b1 b2").contains("unknown band or function b1b2"));
+        assert!(error("(b1 + b2").contains("expected )"));
+        assert!(error("b1 + ").contains("unexpected end of expression"));
+        assert!(error("b1 + b2)").contains("unexpected character )"));
+        assert!(error("b1 % b2").contains("unexpected character %"));
+        assert!(error("1.2.3").contains("invalid number 1.2.3"));
+        assert!(error("sqrt + b1").contains("expected ( at position 4"));
+    }
+}
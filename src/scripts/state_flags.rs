@@ -0,0 +1,125 @@
+use crate::config::Config;
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::{try_read_mesh, Rasters};
+use crate::scripts::output_columns::get_statistic_column;
+use crate::scripts::run::check_if_modis_data_exists;
+
+// (cloud fraction, cloud shadow fraction, snow fraction)
+type StateFlagFractions = (Option<f32>, Option<f32>, Option<f32>);
+
+// decodes the bits of the MOD09A1 sur_refl_state_500m layer
+// bits 0-1: cloud state (00 clear, 01 cloudy, 10 mixed, 11 not set, assumed clear)
+// bit 2: cloud shadow
+// bit 12: MOD35 snow/ice flag, bit 15: internal snow mask
+fn is_cloudy(state: u16) -> bool {
+    [0b01, 0b10].contains(&(state & 0b11))
+}
+
+fn is_cloud_shadow(state: u16) -> bool {
+    (state >> 2) & 0b1 == 1
+}
+
+fn is_snow(state: u16) -> bool {
+    (state >> 12) & 0b1 == 1 || (state >> 15) & 0b1 == 1
+}
+
+fn find_state_flag_fractions(states: &[f64]) -> StateFlagFractions {
+    let states: Vec<u16> = states.iter().map(|x| *x as u16).collect();
+    let pixel_count = states.len() as f32;
+    if pixel_count == 0.0 {
+        return (None, None, None);
+    }
+    let cloudy = states.iter().filter(|x| is_cloudy(**x)).count();
+    let shadow = states.iter().filter(|x| is_cloud_shadow(**x)).count();
+    let snow = states.iter().filter(|x| is_snow(**x)).count();
+    (
+        Some(cloudy as f32 / pixel_count),
+        Some(shadow as f32 / pixel_count),
+        Some(snow as f32 / pixel_count),
+    )
+}
+
+// Pixels of the window around the tower the state layer of the composite doesn't flag as cloudy, cloud shadow or snow,
+// for the surface reflectance bands of the same product and date. None when the state layer can't be read,
+// then every pixel is kept
+pub fn get_clear_pixel_filter(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> Option<Vec<bool>> {
+    let mut state_dm = get_catalog_metadata("sur_refl_state_500m", config);
+    state_dm.product = dm.product.clone();
+
+    let data_qc_paths = check_if_modis_data_exists(
+        year,
+        doy,
+        &config.modis_dir,
+        &state_dm,
+        &mut rasters.lookups,
+    )
+    .ok()?;
+    let (states, _qc) = try_read_mesh(&state_dm, tower_entry_data, data_qc_paths, rasters)?;
+    Some(
+        states
+            .iter()
+            .map(|x| {
+                let state = *x as u16;
+                !is_cloudy(state) && !is_cloud_shadow(state) && !is_snow(state)
+            })
+            .collect(),
+    )
+}
+
+// cloud, cloud shadow and snow fraction columns of the state layer
+const STATE_FLAG_COLUMNS: [&str; 3] = [
+    "sur_refl_cloud_fraction",
+    "sur_refl_cloud_shadow_fraction",
+    "sur_refl_snow_fraction",
+];
+
+pub fn get_state_flag_columns(dataset_name: &str, config: &Config) -> Vec<ColumnDescriptor> {
+    let dm = get_catalog_metadata(dataset_name, config);
+    STATE_FLAG_COLUMNS
+        .iter()
+        .map(|column| get_statistic_column(column, ColumnType::Float32, &dm, "1"))
+        .collect()
+}
+
+// Reads the surface reflectance state layer around the tower and writes the cloud, shadow and snow fractions to the record.
+pub fn get_state_flag_data<'a>(
+    mut rcrd: NewRecord<'a>,
+    dataset_name: &str,
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> NewRecord<'a> {
+    let tower_entry_data = TowerEntryData {
+        year,
+        doy,
+        lat: rcrd.lat,
+        lon: rcrd.lon,
+    };
+    let dm = get_catalog_metadata(dataset_name, config);
+
+    let fractions =
+        match check_if_modis_data_exists(year, doy, &config.modis_dir, &dm, &mut rasters.lookups) {
+            Ok(data_qc_paths) => {
+                match try_read_mesh(&dm, &tower_entry_data, data_qc_paths, rasters) {
+                    Some((states, _qc)) => find_state_flag_fractions(&states),
+                    None => (None, None, None),
+                }
+            }
+            Err(_e) => (None, None, None),
+        };
+
+    let (cloud, shadow, snow) = fractions;
+    for (column, fraction) in STATE_FLAG_COLUMNS.iter().zip([cloud, shadow, snow]) {
+        rcrd.set_value(column, fraction.map(ColumnValue::Float32));
+    }
+    rcrd
+}
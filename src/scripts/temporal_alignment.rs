@@ -0,0 +1,175 @@
+use crate::config::{Config, TemporalAlignment};
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::{try_read_mesh, Rasters};
+use crate::scripts::run::check_if_modis_data_exists;
+use std::path::PathBuf;
+
+// (data and qc paths, pixels of the window to keep, days the value covers)
+type AlignedComposite = ((PathBuf, PathBuf), Option<Vec<bool>>, CompositeWindow);
+
+// reads the composite day of the year layer of a 16 day VI composite around the tower
+fn read_composite_doys(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    year: i32,
+    doy: u32,
+    modis_dir: &str,
+    rasters: &mut Rasters,
+) -> Option<Vec<f64>> {
+    let doy_name = if dm.product.starts_with("VNP") {
+        "VNP_composite_day_of_the_year"
+    } else {
+        "composite_day_of_the_year"
+    };
+    // same layer for MOD13A2 and MYD13A2, only the product changes
+    let mut doy_dm = get_dataset_metadata(doy_name);
+    doy_dm.product = dm.product.clone();
+
+    let data_qc_paths =
+        check_if_modis_data_exists(year, doy, modis_dir, &doy_dm, &mut rasters.lookups).ok()?;
+    // same window as the VI datasets
+    let (composite_doys, _qc) = try_read_mesh(&doy_dm, tower_entry_data, data_qc_paths, rasters)?;
+    Some(composite_doys)
+}
+
+// Finds the composite file that fills the 8 day row starting on doy, following the temporal alignment policy of the dataset.
+// returns the data and qc paths, which pixels of the window to keep for the composite_doy policy and the days the
+// composite covers, which is only the row for composite_doy as the pixels of the other days are left out.
+// 8 day products, and 16 day products with the gaps policy, are only found on the day the composite starts.
+// monthly and annual products are found in check_if_modis_data_exists.
+pub fn find_aligned_composite(
+    dm: &DatasetMetadata,
+    tower_entry_data: &TowerEntryData,
+    year: i32,
+    doy: u32,
+    config: &Config,
+    rasters: &mut Rasters,
+) -> Option<AlignedComposite> {
+    let policy = config
+        .temporal_alignment
+        .get(&dm.dataset)
+        .copied()
+        .unwrap_or_default();
+    if dm.composite_step == CompositeStep::Daily {
+        // daily products use the first day of the row that has a file
+        let row_end = doy + composite_days(year, doy) - 1;
+        return (doy..=row_end).find_map(|d| {
+            check_if_modis_data_exists(year, d, &config.modis_dir, dm, &mut rasters.lookups)
+                .ok()
+                .map(|data_qc_paths| {
+                    let window = get_composite_window(year, d, CompositeStep::Daily);
+                    (data_qc_paths, None, window)
+                })
+        });
+    }
+    if dm.composite_step != CompositeStep::SixteenDay || policy == TemporalAlignment::Gaps {
+        return check_if_modis_data_exists(year, doy, &config.modis_dir, dm, &mut rasters.lookups)
+            .ok()
+            .map(|data_qc_paths| {
+                let window = get_composite_window(year, doy, dm.composite_step);
+                (data_qc_paths, None, window)
+            });
+    }
+
+    // a 16 day composite covers the row it starts on and the next one, so the row is filled
+    // either by a composite starting on it or by one starting on the row before.
+    // the row before the first row of a year is the last row (doy 361) of the previous year
+    let previous_row = if doy > 8 {
+        (year, doy - 8)
+    } else {
+        (year - 1, get_row_doys().last().unwrap())
+    };
+    let ((composite_year, composite_doy), data_qc_paths) =
+        [(year, doy), previous_row].into_iter().find_map(|(y, d)| {
+            check_if_modis_data_exists(y, d, &config.modis_dir, dm, &mut rasters.lookups)
+                .ok()
+                .map(|data_qc_paths| ((y, d), data_qc_paths))
+        })?;
+
+    match policy {
+        TemporalAlignment::CompositeDoy => {
+            let composite_doys = read_composite_doys(
+                dm,
+                tower_entry_data,
+                composite_year,
+                composite_doy,
+                &config.modis_dir,
+                rasters,
+            )?;
+            let row_end = doy + composite_days(year, doy) - 1;
+            let pixel_filter = composite_doys
+                .iter()
+                .map(|&x| x >= doy as f64 && x <= row_end as f64)
+                .collect();
+            let window = get_composite_window(year, doy, CompositeStep::EightDay);
+            Some((data_qc_paths, Some(pixel_filter), window))
+        }
+        _ => {
+            let window =
+                get_composite_window(composite_year, composite_doy, CompositeStep::SixteenDay);
+            Some((data_qc_paths, None, window))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::fs;
+
+    fn date(doy: u32) -> NaiveDate {
+        NaiveDate::from_yo_opt(2010, doy).unwrap()
+    }
+
+    #[test]
+    fn sixteen_day_composites_follow_the_policy() {
+        let modis_dir =
+            std::env::temp_dir().join(format!("temporal_alignment_{}", std::process::id()));
+        let modis_dir = modis_dir.to_str().unwrap().to_string();
+        let dm = get_dataset_metadata("NDVI");
+        // only the composite starting on doy 17 is there
+        let product_dir = PathBuf::from(&modis_dir).join("MOD13A2.061/1km_org");
+        let data_path = product_dir.join("MOD13A2.061.2010.01.17.1_km_16_days_NDVI.bsq");
+        let qc_path = product_dir.join("MOD13A2.061.2010.01.17.1_km_16_days_VI_Quality.bsq");
+        fs::create_dir_all(&product_dir).unwrap();
+        for path in [&data_path, &qc_path] {
+            fs::File::create(path).unwrap();
+        }
+        let tower = TowerEntryData {
+            year: 2010,
+            doy: 17,
+            lat: 36.14,
+            lon: 137.37,
+        };
+        let mut config: Config = toml::from_str(&format!("modis_dir = {:?}", modis_dir)).unwrap();
+
+        let mut rasters = Rasters::default();
+        let mut find = |doy: u32, config: &Config| {
+            find_aligned_composite(&dm, &tower, 2010, doy, config, &mut rasters)
+                .map(|(paths, pixel_filter, window)| (paths, pixel_filter.is_some(), window))
+        };
+        // gaps only fills the row the composite starts on
+        assert_eq!(
+            find(17, &config),
+            Some((
+                (data_path.clone(), qc_path.clone()),
+                false,
+                (date(17), date(32))
+            ))
+        );
+        assert_eq!(find(25, &config), None);
+        assert_eq!(find(9, &config), None);
+        // repeat also fills the next row with the same composite, and the same window
+        config
+            .temporal_alignment
+            .insert("NDVI".to_string(), TemporalAlignment::Repeat);
+        assert_eq!(
+            find(25, &config),
+            Some(((data_path, qc_path), false, (date(17), date(32))))
+        );
+        assert_eq!(find(33, &config), None);
+        fs::remove_dir_all(&modis_dir).unwrap();
+    }
+}
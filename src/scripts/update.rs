@@ -0,0 +1,390 @@
+use crate::config::{Config, OutputFormat, OutputLayout};
+use crate::data::*;
+use crate::scripts::define_metadata::*;
+use crate::scripts::get_modis_data::get_modis_values;
+use crate::scripts::output_columns::{get_column_descriptors, parse_column_value};
+use crate::scripts::provenance::{
+    get_file_info, read_provenance_config, read_provenance_lookups, FileInfo,
+};
+use chrono::{Datelike, Duration, Months, NaiveDate};
+use csv::ReaderBuilder;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    path::Path,
+};
+
+// `update flux.csv [config.toml]` reads the rows back from the site files, so they need every column
+pub fn check_update_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.output_format != OutputFormat::Csv
+        || config.output_layout != OutputLayout::Wide
+        || config.merge_sites
+        || config.output_columns.is_some()
+    {
+        return Err(From::from(
+            "update needs the per site wide csv output with every column (no merge_sites or output_columns)",
+        ));
+    }
+    Ok(())
+}
+
+// The rows of an earlier {site}.csv by date, every column read back as the type get_column_descriptors gives it.
+// no rows when the site has no output yet
+pub fn read_output_rows<'a>(
+    site_code: &'a str,
+    path: &Path,
+    config: &Config,
+) -> Result<BTreeMap<NaiveDate, NewRecord<'a>>, Box<dyn Error>> {
+    let mut rows = BTreeMap::new();
+    if !path.exists() {
+        return Ok(rows);
+    }
+    let mut rdr = ReaderBuilder::new().from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    // (column, its position in the file) of the columns read from the rasters, the others are NewRecord fields
+    let mut value_columns = vec![];
+    for column in get_column_descriptors(config) {
+        if [ColumnKind::Site, ColumnKind::Flux].contains(&column.kind) {
+            continue;
+        }
+        match headers.iter().position(|header| header == column.name) {
+            Some(i) => value_columns.push((column, i)),
+            None => {
+                return Err(From::from(format!(
+                    "{} has no {} column, it was written with a different config",
+                    path.display(),
+                    column.name
+                )))
+            }
+        }
+    }
+    for record in rdr.records() {
+        let record = record?;
+        let mut rcrd: NewRecord = record.deserialize(Some(&headers))?;
+        rcrd.site_code = site_code;
+        for (column, i) in &value_columns {
+            let value = parse_column_value(&record[*i], column)
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            rcrd.set_value(&column.name, value);
+        }
+        rows.insert(NaiveDate::from_yo_opt(rcrd.year, rcrd.doy).unwrap(), rcrd);
+    }
+    Ok(rows)
+}
+
+// config keys that only change which files are written or what is worked out from the rows after they are read
+// (smoothing, daily, phenology). the rest change the values read from the rasters
+const OUTPUT_ONLY_KEYS: [&str; 22] = [
+    "output_dir",
+    "output_format",
+    "merge_sites",
+    "output_layout",
+    "start_date",
+    "end_date",
+    "tower_period_only",
+    "tower_period_margin",
+    "write_sensor_comparison",
+    "daily_interpolation",
+    "daily_min_goodpix",
+    "smoothing",
+    "savitzky_golay_half_window",
+    "savitzky_golay_order",
+    "whittaker_lambda",
+    "upper_envelope_iterations",
+    "write_phenology",
+    "phenology_onset_fraction",
+    "phenology_maturity_fraction",
+    "missing_values",
+    "provenance_checksums",
+    "extraction_cache",
+];
+
+// keys of the config an earlier output was written with that differ from the current config, leaving out OUTPUT_ONLY_KEYS
+fn get_changed_config_keys(
+    old_config: &serde_json::Value,
+    config: &Config,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let config = serde_json::to_value(config)?;
+    let (Some(old_config), Some(config)) = (old_config.as_object(), config.as_object()) else {
+        return Err(From::from("provenance sidecar config isn't an object"));
+    };
+    Ok(config
+        .keys()
+        .chain(old_config.keys())
+        .filter(|key| !OUTPUT_ONLY_KEYS.contains(&key.as_str()))
+        .filter(|key| old_config.get(*key) != config.get(*key))
+        .cloned()
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect())
+}
+
+fn is_changed(old: &FileInfo, new: &FileInfo) -> bool {
+    old.size != new.size || old.modified != new.modified
+}
+
+// last day a file of a dataset covers, from its date
+fn get_composite_end(date: NaiveDate, composite_step: CompositeStep) -> NaiveDate {
+    match composite_step {
+        CompositeStep::Daily => date,
+        CompositeStep::EightDay => date + Duration::days(7),
+        CompositeStep::SixteenDay => date + Duration::days(15),
+        CompositeStep::Monthly => (date + Months::new(1)).pred_opt().unwrap(),
+        CompositeStep::Annual => NaiveDate::from_ymd_opt(date.year(), 12, 31).unwrap(),
+    }
+}
+
+// Dates of the rows of an earlier output to read again.
+// with a provenance sidecar these are the rows a composite that was missing and has arrived since,
+// or that was found and changed size or modification time (reprocessed), covers part of.
+// every row is read again when the sidecar's config reads the rasters differently (units, alignment, qc, catalog),
+// so a file never mixes the two. outputs without a sidecar fall back to the rows after the last date each dataset has a value,
+// for the datasets that are at most one composite behind the newest row
+pub fn get_update_dates(
+    site_code: &str,
+    output_rows: &BTreeMap<NaiveDate, NewRecord>,
+    output_path: &Path,
+    config: &Config,
+) -> Result<BTreeSet<NaiveDate>, Box<dyn Error>> {
+    let mut update_dates = BTreeSet::new();
+    if let Some(old_config) = read_provenance_config(output_path) {
+        let changed_keys = get_changed_config_keys(&old_config, config)?;
+        if !changed_keys.is_empty() {
+            println!(
+                "{site_code}: the config changed since {} was written ({}), every row is read again",
+                output_path.display(),
+                changed_keys.join(", ")
+            );
+            return Ok(output_rows.keys().copied().collect());
+        }
+    }
+    let raster_lookups = read_provenance_lookups(output_path);
+
+    if raster_lookups.is_empty() {
+        let Some(newest_date) = output_rows.keys().next_back().copied() else {
+            return Ok(update_dates);
+        };
+        for dataset_name in get_dataset_names()
+            .into_iter()
+            .chain(get_catalog_dataset_names(config))
+        {
+            // datasets the site never had a value for aren't waited on
+            let Some(last_date) = output_rows
+                .iter()
+                .rev()
+                .find(|(_, rcrd)| get_modis_values(rcrd, &dataset_name, config).0.is_some())
+                .map(|(date, _)| *date)
+            else {
+                continue;
+            };
+            // nor are datasets whose values stopped more than one composite before the newest row,
+            // their gaps are missing data rather than composites still to come. daily files fill whole rows
+            let composite_step = match get_catalog_metadata(&dataset_name, config).composite_step {
+                CompositeStep::Daily => CompositeStep::EightDay,
+                composite_step => composite_step,
+            };
+            let next_start = get_composite_end(last_date, composite_step) + Duration::days(1);
+            if newest_date > get_composite_end(next_start, composite_step) {
+                continue;
+            }
+            update_dates.extend(
+                output_rows
+                    .range(last_date..)
+                    .skip(1)
+                    .map(|(date, _)| *date),
+            );
+        }
+        println!(
+            "{site_code}: no provenance sidecar, {} rows after the last value of a dataset are read again",
+            update_dates.len()
+        );
+        return Ok(update_dates);
+    }
+
+    let mut changed = 0;
+    for raster_lookup in raster_lookups {
+        let data = get_file_info(Path::new(&raster_lookup.data.path), false);
+        let qc = get_file_info(Path::new(&raster_lookup.qc.path), false);
+        let arrived = raster_lookup.missing.is_some() && data.size.is_some() && qc.size.is_some();
+        let reprocessed = raster_lookup.missing.is_none()
+            && (is_changed(&raster_lookup.data, &data) || is_changed(&raster_lookup.qc, &qc));
+        if !arrived && !reprocessed {
+            continue;
+        }
+        let Ok(file_date) = NaiveDate::parse_from_str(&raster_lookup.date, "%Y.%m.%d") else {
+            continue;
+        };
+        changed += 1;
+        let composite_step = get_catalog_metadata(&raster_lookup.dataset, config).composite_step;
+        let file_end = get_composite_end(file_date, composite_step);
+        // every row the composite overlaps could have read it
+        for row_date in output_rows.keys() {
+            let row_end = *row_date
+                + Duration::days(composite_days(row_date.year(), row_date.ordinal()) as i64 - 1);
+            if file_date <= row_end && file_end >= *row_date {
+                update_dates.insert(*row_date);
+            }
+        }
+    }
+    println!(
+        "{site_code}: {changed} composites arrived or changed, {} rows are read again",
+        update_dates.len()
+    );
+    Ok(update_dates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripts::provenance::{
+        record_raster_lookup, take_raster_lookups, write_provenance, RasterLookups,
+    };
+    use crate::scripts::run::get_modis_paths;
+    use std::fs;
+
+    fn date(doy: u32) -> NaiveDate {
+        NaiveDate::from_yo_opt(2010, doy).unwrap()
+    }
+
+    // rows 1 - 33 of 2010, with ndvi up to ndvi_doy and gpp_modis up to gpp_doy
+    fn output_rows(ndvi_doy: u32, gpp_doy: u32) -> BTreeMap<NaiveDate, NewRecord<'static>> {
+        [1, 9, 17, 25, 33]
+            .into_iter()
+            .map(|doy| {
+                let mut rcrd = NewRecord {
+                    site_code: "JP-Tkc",
+                    year: 2010,
+                    doy,
+                    ..Default::default()
+                };
+                if doy <= ndvi_doy {
+                    rcrd.set_value("ndvi", Some(ColumnValue::Float64(0.5)));
+                }
+                if doy <= gpp_doy {
+                    rcrd.set_value("gpp_modis", Some(ColumnValue::Float64(3.0)));
+                }
+                (date(doy), rcrd)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn output_only_keys_are_not_changes() {
+        let config: Config = toml::from_str("").unwrap();
+        let old_config: Config = toml::from_str(
+            "output_dir = \"elsewhere\"\nsmoothing = \"whittaker\"\net_units = \"mm_per_day\"",
+        )
+        .unwrap();
+        let mut old_config = serde_json::to_value(old_config).unwrap();
+        assert_eq!(
+            get_changed_config_keys(&old_config, &config).unwrap(),
+            vec!["et_units".to_string()]
+        );
+        // a key the current config no longer has
+        old_config
+            .as_object_mut()
+            .unwrap()
+            .insert("retired_key".to_string(), serde_json::Value::Bool(true));
+        assert_eq!(
+            get_changed_config_keys(&old_config, &config).unwrap(),
+            vec!["et_units".to_string(), "retired_key".to_string()]
+        );
+    }
+
+    #[test]
+    fn without_a_sidecar_rows_after_the_last_recent_value_are_read_again() {
+        let config: Config = toml::from_str("").unwrap();
+        let output_path =
+            std::env::temp_dir().join(format!("update_no_sidecar_{}.csv", std::process::id()));
+        let update_dates =
+            get_update_dates("JP-Tkc", &output_rows(9, 17), &output_path, &config).unwrap();
+        assert_eq!(update_dates, BTreeSet::from([date(17), date(25), date(33)]));
+        // the 16 day ndvi ending on day 16 is more than a composite behind the newest row, 8 day gpp isn't
+        let update_dates =
+            get_update_dates("JP-Tkc", &output_rows(1, 25), &output_path, &config).unwrap();
+        assert_eq!(update_dates, BTreeSet::from([date(33)]));
+    }
+
+    #[test]
+    fn rows_covered_by_arrived_or_changed_composites_are_read_again() {
+        let dir = std::env::temp_dir().join(format!("update_sidecar_{}", std::process::id()));
+        let config_toml = format!(
+            "modis_dir = {:?}\noutput_dir = {:?}",
+            dir.join("modis").to_str().unwrap(),
+            dir.to_str().unwrap()
+        );
+        let config: Config = toml::from_str(&config_toml).unwrap();
+        let dm = get_catalog_metadata("NDVI", &config);
+        let create = |path: &Path, contents: &[u8]| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        // the composite of doy 1 was read, the one of doy 17 was missing
+        let mut raster_lookups = RasterLookups::default();
+        let (found_date, found_data, found_qc) =
+            get_modis_paths(2010, 1, &config.modis_dir, &dm).unwrap();
+        create(&found_data, b"data");
+        create(&found_qc, b"qc");
+        record_raster_lookup(
+            &mut raster_lookups,
+            &dm,
+            &found_date,
+            &found_data,
+            &found_qc,
+            None,
+        );
+        let (missing_date, missing_data, missing_qc) =
+            get_modis_paths(2010, 17, &config.modis_dir, &dm).unwrap();
+        record_raster_lookup(
+            &mut raster_lookups,
+            &dm,
+            &missing_date,
+            &missing_data,
+            &missing_qc,
+            Some("No data file or QC file found"),
+        );
+        let output_path = dir.join("JP-Tkc.csv");
+        let flux_file = FileInfo {
+            path: "flux.csv".to_string(),
+            size: None,
+            modified: None,
+            sha256: None,
+        };
+        write_provenance(
+            std::slice::from_ref(&output_path),
+            &["JP-Tkc"],
+            &flux_file,
+            take_raster_lookups(&mut raster_lookups),
+            &config,
+        )
+        .unwrap();
+        let rows = output_rows(33, 33);
+
+        assert!(get_update_dates("JP-Tkc", &rows, &output_path, &config)
+            .unwrap()
+            .is_empty());
+        // the composite of doy 17 covers jan 17 - feb 1
+        create(&missing_data, b"data");
+        create(&missing_qc, b"qc");
+        assert_eq!(
+            get_update_dates("JP-Tkc", &rows, &output_path, &config).unwrap(),
+            BTreeSet::from([date(17), date(25)])
+        );
+        // the composite of doy 1 was reprocessed
+        create(&found_data, b"reprocessed data");
+        assert_eq!(
+            get_update_dates("JP-Tkc", &rows, &output_path, &config).unwrap(),
+            BTreeSet::from([date(1), date(9), date(17), date(25)])
+        );
+        // a config that reads the rasters differently reads every row
+        let changed_config: Config =
+            toml::from_str(&format!("{config_toml}\net_units = \"mm_per_day\"")).unwrap();
+        assert_eq!(
+            get_update_dates("JP-Tkc", &rows, &output_path, &changed_config)
+                .unwrap()
+                .len(),
+            5
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}